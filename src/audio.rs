@@ -1,114 +1,646 @@
-// Construct CPAL stuff
+// Construct CPAL stuff, or something to stand in for it
 
 use std::fmt::Display;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use web_time::{Duration, Instant};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     FromSample, Sample, SizedSample,
 };
 
-use crate::constants::*;
+use crate::dither::*;
+use crate::effects::*;
+use crate::granular::Granular;
+use crate::wavetable::Wavetable;
+use crate::realtime::realtime;
+use crate::recorder::*;
+use crate::settings::Settings;
+use crate::synth::*;
+use crate::wav::*;
+use crate::worker::WorkerThread;
 
-#[cfg(feature = "audio_log")]
-use std::io::Write;
-#[cfg(feature = "audio_log")]
-type AudioLog = std::fs::File;
-#[cfg(not(feature = "audio_log"))]
-type AudioLog = ();
+// Output controls, shared between whoever is turning the knobs and the audio thread
+#[derive(Debug)]
+pub struct AudioControls {
+    gain_db: AtomicU32, // f32 bits
+    muted: AtomicBool,
+    recording: AtomicBool,
+}
+
+impl AudioControls {
+    pub fn new(gain_db: f32, muted: bool, recording: bool) -> Self {
+        AudioControls { gain_db: AtomicU32::new(gain_db.to_bits()), muted: AtomicBool::new(muted), recording: AtomicBool::new(recording) }
+    }
+
+    pub fn gain_db(&self) -> f32 { f32::from_bits(self.gain_db.load(Ordering::Relaxed)) }
+    pub fn set_gain_db(&self, gain_db: f32) { self.gain_db.store(gain_db.to_bits(), Ordering::Relaxed) }
+    pub fn muted(&self) -> bool { self.muted.load(Ordering::Relaxed) }
+    pub fn set_muted(&self, muted: bool) { self.muted.store(muted, Ordering::Relaxed) }
+    pub fn recording(&self) -> bool { self.recording.load(Ordering::Relaxed) }
+    pub fn set_recording(&self, recording: bool) { self.recording.store(recording, Ordering::Relaxed) }
+
+    // Linear gain the output should be heading toward
+    fn target_gain(&self) -> f32 {
+        if self.muted() { 0. } else { db_to_linear(self.gain_db()) }
+    }
+}
+
+// Time constant for gain changes; long enough not to click, short enough to feel immediate
+const AUDIO_GAIN_SMOOTHING:f32 = 0.01;
+
+// Below this a fading-out gain snaps to zero, and we stop asking the synthesizer for samples
+const AUDIO_GAIN_FLOOR:f32 = 1e-5;
+
+// Per-sample smoothing of the master gain
+struct GainRamp {
+    controls: Arc<AudioControls>,
+    current: f32,
+    coeff: f32,
+}
+
+impl GainRamp {
+    fn new(controls: Arc<AudioControls>, sample_rate: f32) -> Self {
+        GainRamp {
+            current: controls.target_gain(),
+            coeff: smoothing_coeff(AUDIO_GAIN_SMOOTHING, sample_rate),
+            controls,
+        }
+    }
+
+    fn next(&mut self, target: f32) -> f32 {
+        self.current += (target - self.current)*self.coeff;
+        if target == 0. && self.current < AUDIO_GAIN_FLOOR {
+            self.current = 0.;
+        }
+        self.current
+    }
+}
+
+// Settings for the output stage, which sits between the synthesizer and the device
+#[derive(Clone, Debug)]
+pub struct OutputConfig {
+    pub agc: bool,
+    pub agc_target_db: f32,      // RMS level, in dBFS, automatic gain aims for
+    pub agc_attack: f32,         // Seconds for automatic gain to come down when things get louder
+    pub agc_release: f32,        // Seconds for automatic gain to come back up when things get quieter
+    pub agc_max_gain_db: f32,    // Never boost by more than this, so near-silence isn't pumped up into hiss
+    pub dc_cutoff: f32,          // Hz; 0 disables the DC blocker
+    pub limiter_ceiling_db: f32, // Nothing leaves the output stage louder than this, in dBFS
+    pub limiter_lookahead: f32,  // Seconds. Also the latency the limiter adds
+    pub limiter_release: f32,    // Seconds for the limiter to let go after a peak
+    pub bits: Option<u32>,       // Reduce output to this bit depth, if the device format isn't already narrower
+    pub dither: DitherMode,      // How output narrower than 32 bits (and recordings) are rounded
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            agc: true,
+            agc_target_db: -20.,
+            agc_attack: 0.05,
+            agc_release: 1.,
+            agc_max_gain_db: 30.,
+            dc_cutoff: 10.,
+            limiter_ceiling_db: -1.,
+            limiter_lookahead: 0.005,
+            limiter_release: 0.1,
+            bits: None,
+            dither: DitherMode::Tpdf,
+        }
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db/20.)
+}
+
+// Coefficient for a one-pole filter with the given time constant
+fn smoothing_coeff(seconds: f32, sample_rate: f32) -> f32 {
+    if seconds <= 0. { 1. } else { 1. - (-1. / (seconds*sample_rate)).exp() }
+}
+
+// Time constant of the RMS measurement automatic gain works from
+const AUDIO_AGC_RMS_WINDOW:f32 = 0.05;
+
+// Below this RMS (dBFS) automatic gain assumes it's hearing silence, and holds still rather than boosting it
+const AUDIO_AGC_GATE_DB:f32 = -60.;
+
+// One-pole high-pass that removes any DC offset
+struct DcBlocker {
+    coeff: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+impl DcBlocker {
+    fn new(cutoff: f32, sample_rate: f32) -> Self {
+        DcBlocker { coeff: (-2. * std::f32::consts::PI * cutoff / sample_rate).exp(), last_in: 0., last_out: 0. }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.last_out = sample - self.last_in + self.coeff*self.last_out;
+        self.last_in = sample;
+        self.last_out
+    }
+}
+
+// Slowly steers the RMS level toward a target, so loudness doesn't depend on how much of the screen is lit.
+// Both channels get the same gain, so the stereo image holds still.
+struct AutoGain {
+    enabled: bool,
+    target: f32,
+    max_gain: f32,
+    gate: f32,
+    power: f32, // Mean square, smoothed
+    power_coeff: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    gain: f32,
+}
+
+impl AutoGain {
+    fn new(config: &OutputConfig, sample_rate: f32) -> Self {
+        AutoGain {
+            enabled: config.agc,
+            target: db_to_linear(config.agc_target_db),
+            max_gain: db_to_linear(config.agc_max_gain_db),
+            gate: db_to_linear(AUDIO_AGC_GATE_DB),
+            power: 0.,
+            power_coeff: smoothing_coeff(AUDIO_AGC_RMS_WINDOW, sample_rate),
+            attack_coeff: smoothing_coeff(config.agc_attack, sample_rate),
+            release_coeff: smoothing_coeff(config.agc_release, sample_rate),
+            gain: 1.,
+        }
+    }
+
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        if !self.enabled { return frame }
+        let power = (frame[0]*frame[0] + frame[1]*frame[1]) / 2.;
+        self.power += (power - self.power)*self.power_coeff;
+        let rms = self.power.sqrt();
+        if rms > self.gate {
+            let target = (self.target / rms).min(self.max_gain);
+            let coeff = if target < self.gain { self.attack_coeff } else { self.release_coeff };
+            self.gain += (target - self.gain)*coeff;
+        }
+        frame.map(|sample| sample * self.gain)
+    }
+}
+
+// Brickwall limiter. Input is delayed by the lookahead, so the gain can start coming down before a peak arrives
+// and ramp smoothly to exactly what the peak needs: the gain each sample needs is held across the lookahead window,
+// then averaged over the lookahead window, and every value in that average is already low enough for the peak.
+// Linked: the louder channel decides the gain for both.
+struct Limiter {
+    ceiling: f32,
+    release_coeff: f32,
+    delay: VecDeque<[f32; 2]>,
+    required: VecDeque<(u64, f32)>, // Gains samples in the window need, increasing; so the front is the minimum
+    envelope: f32,
+    smoothing: VecDeque<f32>, // Last lookahead envelope values
+    smoothing_sum: f64,
+    sample_idx: u64,
+}
+
+impl Limiter {
+    fn new(config: &OutputConfig, sample_rate: f32) -> Self {
+        let lookahead = ((config.limiter_lookahead*sample_rate).round() as usize).max(1);
+        let mut delay = VecDeque::with_capacity(lookahead+1);
+        delay.extend(std::iter::repeat_n([0.; 2], lookahead));
+        let mut smoothing = VecDeque::with_capacity(lookahead+1);
+        smoothing.extend(std::iter::repeat_n(1., lookahead));
+        Limiter {
+            ceiling: db_to_linear(config.limiter_ceiling_db),
+            release_coeff: smoothing_coeff(config.limiter_release, sample_rate),
+            delay,
+            required: VecDeque::with_capacity(lookahead+2), // Pushed to before it's trimmed
+            envelope: 1.,
+            smoothing,
+            smoothing_sum: lookahead as f64,
+            sample_idx: 0,
+        }
+    }
+
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let lookahead = self.smoothing.len();
+        let magnitude = frame[0].abs().max(frame[1].abs());
+        let required = if magnitude > self.ceiling { self.ceiling / magnitude } else { 1. };
+
+        // Minimum over this sample and the lookahead before it
+        while self.required.back().is_some_and(|&(_, r)| r >= required) {
+            self.required.pop_back();
+        }
+        self.required.push_back((self.sample_idx, required));
+        while self.required.front().is_some_and(|&(idx, _)| idx + (lookahead as u64) < self.sample_idx) {
+            self.required.pop_front();
+        }
+        let hold = self.required.front().map_or(1., |&(_, r)| r);
+        self.sample_idx += 1;
+
+        self.envelope = (self.envelope + (1. - self.envelope)*self.release_coeff).min(hold);
+        self.smoothing_sum += (self.envelope - self.smoothing.pop_front().unwrap_or(1.)) as f64;
+        self.smoothing.push_back(self.envelope);
+        let gain = (self.smoothing_sum / lookahead as f64) as f32;
+
+        self.delay.push_back(frame);
+        let delayed = self.delay.pop_front().unwrap_or_default();
+        // The clamp only matters if rounding in the running sum lets something through
+        delayed.map(|sample| (sample * gain).clamp(-self.ceiling, self.ceiling))
+    }
+}
+
+// Samples synthesized and run through the effects chain at a time
+const AUDIO_BLOCK_LEN:usize = 256;
+
+// Everything between the synthesizer and the device: DC blocking, effects, automatic gain, master gain, the limiter,
+// and dither down to the device's bit depth. In stereo; the pairs are left, then right.
+struct OutputStage {
+    dc: [DcBlocker; 2],
+    effects: [EffectChain; 2],
+    agc: AutoGain,
+    gain: GainRamp,
+    limiter: Limiter,
+    dither: [Dither; 2],
+    bits: Option<u32>, // See output_bits
+}
 
-fn audio_write<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32, audio_log: &mut AudioLog)
+impl OutputStage {
+    fn new(config: &OutputConfig, effects: &[EffectConfig], controls: Arc<AudioControls>, sample_rate: f32, bits: Option<u32>) -> Self {
+        OutputStage {
+            dc: std::array::from_fn(|_| DcBlocker::new(config.dc_cutoff, sample_rate)),
+            effects: std::array::from_fn(|_| EffectChain::new(effects, sample_rate)),
+            agc: AutoGain::new(config, sample_rate),
+            gain: GainRamp::new(controls, sample_rate),
+            limiter: Limiter::new(config, sample_rate),
+            dither: std::array::from_fn(|_| Dither::new(config.dither)),
+            bits,
+        }
+    }
+}
+
+// Bits per sample of an integer sample format; None for float
+fn format_bits(format: cpal::SampleFormat) -> Option<u32> {
+    if format.is_float() { None } else { Some(format.sample_size() as u32 * 8) }
+}
+
+// The bit depth output gets dithered down to, if any: the device format's or the one asked for, whichever is narrower.
+// 32 bits and up is already finer than the f32 samples we make, so is left alone.
+fn output_bits(format_bits: Option<u32>, requested: Option<u32>) -> Option<u32> {
+    let bits = match (format_bits, requested) {
+        (Some(format_bits), Some(requested)) => Some(format_bits.min(requested)),
+        (format_bits, requested) => format_bits.or(requested)
+    };
+    bits.filter(|&bits| bits < 32)
+}
+
+// Stereo frames out to a device with the given number of channels. A mono device gets left and right mixed together;
+// past the first two, channels repeat left and right.
+fn audio_write<T>(output: &mut [T], channels: usize, next_frame: &mut dyn FnMut() -> [f32; 2], stage: &mut OutputStage, recorder: &mut Option<RecorderInput>)
 where
-    T: Sample + FromSample<f32> + bytemuck::Pod, /* Pod constraint can be removed without audio_log */
+    T: Sample + FromSample<f32>,
 {
-    // Chop output array into slices of size "channels"
-    for frame in output.chunks_mut(channels) {
-        let value: T = T::from_sample(next_sample());
+    let target = stage.gain.controls.target_gain();
+    let mut blocks = [[0f32; AUDIO_BLOCK_LEN]; 2];
 
-        // Take one sample and interleave it into all channels
-        for sample in frame.iter_mut() {
-            *sample = value;
+    // Chop output array into slices of size "channels", a block's worth at a time
+    for frames in output.chunks_mut(channels*AUDIO_BLOCK_LEN) {
+        let len = frames.len().div_ceil(channels);
+
+        // Once fully muted, don't bother taking samples at all (automatic gain holds still, and the limiter drains)
+        let silent = target == 0. && stage.gain.current == 0.;
+        if silent {
+            for block in blocks.iter_mut() {
+                block[..len].fill(0.);
+            }
+        } else {
+            for idx in 0..len {
+                let frame = next_frame();
+                for (channel, block) in blocks.iter_mut().enumerate() {
+                    block[idx] = stage.dc[channel].process(frame[channel]);
+                }
+            }
+            for (block, effects) in blocks.iter_mut().zip(stage.effects.iter_mut()) {
+                effects.process(&mut block[..len]);
+            }
         }
 
-        #[cfg(feature = "audio_log")]
-        {
-            audio_log.write_all(bytemuck::cast_slice(&[value]));
+        for (idx, frame) in frames.chunks_mut(channels).enumerate() {
+            let gain = stage.gain.next(target);
+            let raw = if silent { [0.; 2] } else { stage.agc.process([blocks[0][idx], blocks[1][idx]]).map(|sample| sample * gain) };
+            let mut raw = stage.limiter.process(raw);
+            if channels == 1 {
+                raw = [(raw[0] + raw[1]) / 2.; 2];
+            }
+            let values: [T; 2] = std::array::from_fn(|channel| T::from_sample(match stage.bits {
+                Some(bits) => stage.dither[channel].reduce(raw[channel], bits),
+                None => raw[channel]
+            }));
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = values[channel % 2];
+            }
+
+            if let Some(recorder) = recorder {
+                recorder.push(raw);
+            }
         }
     }
 }
 
 #[derive(Debug)]
-enum CpalError {
+pub enum AudioError {
 	Build(cpal::BuildStreamError),
 	Play(cpal::PlayStreamError),
+	Devices(cpal::DevicesError),
+	Configs(cpal::SupportedStreamConfigsError),
+	DefaultConfig(cpal::DefaultStreamConfigError),
+	HostUnavailable(cpal::HostId),
+	NoHost(String),
     NoDevice,
+	NoMatchingDevice(String),
+	NoInputDevice,
+	NoMatchingInputDevice(String),
+	UnsupportedFormat(cpal::SampleFormat),
+	BackendUnavailable,
+	Io(std::io::Error),
+	GrainFile(PathBuf, std::io::Error),
 	Unknown
 }
 
-impl std::error::Error for CpalError {}
-impl Display for CpalError {
+impl std::error::Error for AudioError {}
+impl Display for AudioError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    	write!(f, "{:?}", self)
+        match self {
+            AudioError::Build(e) => write!(f, "{}", e),
+            AudioError::Play(e) => write!(f, "{}", e),
+            AudioError::Devices(e) => write!(f, "{}", e),
+            AudioError::Configs(e) => write!(f, "{}", e),
+            AudioError::DefaultConfig(e) => write!(f, "{}", e),
+            AudioError::HostUnavailable(id) => write!(f, "Audio host '{}' is unavailable", id.name()),
+            AudioError::NoHost(name) => write!(f, "No audio host matches '{}' (try --list-devices)", name),
+            AudioError::NoDevice => write!(f, "No audio output device"),
+            AudioError::NoMatchingDevice(name) => write!(f, "No audio output device matches '{}' (try --list-devices)", name),
+            AudioError::NoInputDevice => write!(f, "No audio input device"),
+            AudioError::NoMatchingInputDevice(name) => write!(f, "No audio input device matches '{}' (try --list-devices)", name),
+            AudioError::UnsupportedFormat(format) => write!(f, "Unsupported sample format '{}'", format),
+            AudioError::BackendUnavailable => write!(f, "Only the cpal audio backend is available on this platform"),
+            AudioError::Io(e) => write!(f, "{}", e),
+            AudioError::GrainFile(path, e) => write!(f, "Couldn't load grains from {}: {}", path.display(), e),
+            _ => write!(f, "{:?}", self)
+        }
+    }
+}
+impl From<cpal::BuildStreamError> for AudioError { fn from(e: cpal::BuildStreamError) -> Self { AudioError::Build(e) } }
+impl From<cpal::PlayStreamError> for AudioError { fn from(e: cpal::PlayStreamError) -> Self { AudioError::Play(e) } }
+impl From<cpal::DevicesError> for AudioError { fn from(e: cpal::DevicesError) -> Self { AudioError::Devices(e) } }
+impl From<cpal::SupportedStreamConfigsError> for AudioError { fn from(e: cpal::SupportedStreamConfigsError) -> Self { AudioError::Configs(e) } }
+impl From<cpal::DefaultStreamConfigError> for AudioError { fn from(e: cpal::DefaultStreamConfigError) -> Self { AudioError::DefaultConfig(e) } }
+impl From<std::io::Error> for AudioError { fn from(e: std::io::Error) -> Self { AudioError::Io(e) } }
+
+// Where synthesized audio goes
+#[derive(Default, Debug, Clone, PartialEq)]
+pub enum AudioBackend {
+    #[default]
+    Cpal,          // A real sound device
+    Null,          // Nowhere, but consumed at the pace a device would
+    File(PathBuf), // A WAV file, written at the pace a device would
+}
+
+// Sample rate for the Null and File backends when none is requested
+pub const AUDIO_SINK_SAMPLE_RATE:u32 = 48000;
+
+// Samples the Null and File backends synthesize at a time
+const AUDIO_SINK_BLOCK_LEN:usize = 512;
+
+// Frames a device's callback is assumed to ask for at once when its buffer size is left to the host, which doesn't
+// say. Generous, since the synthesis queue has to hold a whole callback's worth.
+const AUDIO_DEVICE_BUFFER_GUESS:usize = 4096;
+
+// Which output device to use, and how to configure it. Anything left None uses the device's default.
+// Host and device may be given by name or by index (as printed by audio_list).
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct AudioDeviceConfig {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+impl AudioDeviceConfig {
+    pub fn is_default(&self) -> bool {
+        *self == AudioDeviceConfig::default()
+    }
+}
+
+pub fn device_name(device: &cpal::Device) -> String {
+    device.name().unwrap_or_else(|_| "(unnamed)".to_string())
+}
+
+// Match a name-or-index against a list of names. Exact name matches win, then unique case-insensitive substrings.
+pub fn choose_by_name(request: &str, names: &[String]) -> Option<usize> {
+    if let Ok(idx) = request.parse::<usize>() {
+        return if idx < names.len() { Some(idx) } else { None };
+    }
+    if let Some(idx) = names.iter().position(|name| name == request) {
+        return Some(idx);
+    }
+    let request = request.to_lowercase();
+    let mut matches = names.iter().enumerate().filter(|(_, name)| name.to_lowercase().contains(&request));
+    match (matches.next(), matches.next()) {
+        (Some((idx, _)), None) => Some(idx),
+        _ => None
+    }
+}
+
+pub fn choose_host(request: &Option<String>) -> Result<cpal::Host, AudioError> {
+    let Some(request) = request else { return Ok(cpal::default_host()) };
+    let hosts = cpal::available_hosts();
+    let names:Vec<String> = hosts.iter().map(|id| id.name().to_string()).collect();
+    let idx = choose_by_name(request, &names).ok_or_else(|| AudioError::NoHost(request.clone()))?;
+    cpal::host_from_id(hosts[idx]).map_err(|_| AudioError::HostUnavailable(hosts[idx]))
+}
+
+fn choose_device(host: &cpal::Host, request: &Option<String>) -> Result<cpal::Device, AudioError> {
+    let Some(request) = request else { return host.default_output_device().ok_or(AudioError::NoDevice) };
+    let mut devices:Vec<cpal::Device> = host.output_devices()?.collect();
+    let names:Vec<String> = devices.iter().map(device_name).collect();
+    let idx = choose_by_name(request, &names).ok_or_else(|| AudioError::NoMatchingDevice(request.clone()))?;
+    Ok(devices.swap_remove(idx))
+}
+
+// Sample formats cpal_spawn can play
+fn format_playable(format: cpal::SampleFormat) -> bool {
+    use cpal::SampleFormat::*;
+    matches!(format, I8 | I16 | I32 | I64 | U8 | U16 | U32 | U64 | F32 | F64)
+}
+
+// Find a supported config satisfying every field the user set, preferring whatever the device would pick by default.
+// If nothing satisfies them all, settle for the nearest thing the device does support, and say so.
+fn choose_config(device: &cpal::Device, device_config: &AudioDeviceConfig) -> Result<(cpal::SampleFormat, cpal::StreamConfig), AudioError> {
+    let default_config = device.default_output_config()?;
+    let AudioDeviceConfig { sample_rate, buffer_size, channels, .. } = *device_config;
+
+    let config = if sample_rate.is_none() && channels.is_none() && buffer_size.is_none() && format_playable(default_config.sample_format()) {
+        default_config.clone()
+    } else {
+        let fits_buffer = |range: &cpal::SupportedStreamConfigRange| match (buffer_size, range.buffer_size()) {
+            (Some(size), cpal::SupportedBufferSize::Range { min, max }) => *min <= size && size <= *max,
+            _ => true // Either we don't care, or the device doesn't say
+        };
+        // How far off each range is from what was asked: channels and octaves of sample rate count the same
+        let mismatch = |range: &cpal::SupportedStreamConfigRange| {
+            let channels = channels.map_or(0., |channels| (range.channels() as f64 - channels as f64).abs());
+            let rate = sample_rate.map_or(0., |rate| {
+                let nearest = rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+                (nearest as f64 / rate as f64).log2().abs()
+            });
+            channels + rate + if fits_buffer(range) { 0. } else { 0.5 }
+        };
+        let mut candidates:Vec<cpal::SupportedStreamConfigRange> = device.supported_output_configs()?
+            .filter(|range| format_playable(range.sample_format()))
+            .collect();
+        // Closest first; then the same sample format as the default; after that, cpal's own idea of what's best
+        candidates.sort_by(|a, b|
+            mismatch(a).total_cmp(&mismatch(b))
+                .then((b.sample_format() == default_config.sample_format()).cmp(&(a.sample_format() == default_config.sample_format())))
+                .then(b.cmp_default_heuristics(a)));
+
+        let Some(range) = candidates.into_iter().next() else {
+            return Err(AudioError::UnsupportedFormat(default_config.sample_format()));
+        };
+        let rate = sample_rate.map_or(default_config.sample_rate(), cpal::SampleRate)
+            .clamp(range.min_sample_rate(), range.max_sample_rate());
+        if mismatch(&range) > 0. {
+            let mut request:Vec<String> = Default::default();
+            if let Some(channels) = channels { request.push(format!("{} channels", channels)) }
+            if let Some(rate) = sample_rate { request.push(format!("{} Hz", rate)) }
+            if let Some(size) = buffer_size { request.push(format!("a buffer of {} frames", size)) }
+            println!("Audio device does not support {} (try --list-devices); using {} channels at {} Hz instead",
+                request.join(", "), range.channels(), rate.0);
+        }
+        range.with_sample_rate(rate)
+    };
+
+    let sample_format = config.sample_format();
+    let buffer_range = config.buffer_size().clone();
+    let mut config:cpal::StreamConfig = config.into();
+    if let Some(size) = buffer_size {
+        config.buffer_size = cpal::BufferSize::Fixed(match buffer_range {
+            cpal::SupportedBufferSize::Range { min, max } => size.clamp(min, max),
+            cpal::SupportedBufferSize::Unknown => size,
+        });
+    }
+    Ok((sample_format, config))
+}
+
+// Print every host, output device and supported config we can see, and the input devices
+pub fn audio_list() {
+    let default_host = cpal::default_host().id();
+    for (host_idx, host_id) in cpal::available_hosts().into_iter().enumerate() {
+        println!("Host {}: {}{}", host_idx, host_id.name(), if host_id == default_host { " (default)" } else { "" });
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(_) => { println!("    (unavailable)"); continue }
+        };
+        let default_device = host.default_output_device().map(|device| device_name(&device));
+        let devices = match host.output_devices() {
+            Ok(devices) => devices,
+            Err(e) => { println!("    {}", e); continue }
+        };
+        for (device_idx, device) in devices.enumerate() {
+            let name = device_name(&device);
+            println!("    Device {}: {}{}", device_idx, name, if Some(&name) == default_device.as_ref() { " (default)" } else { "" });
+            match device.supported_output_configs() {
+                Ok(configs) => for range in configs {
+                    let buffer = match range.buffer_size() {
+                        cpal::SupportedBufferSize::Range { min, max } => format!("buffer {}..{}", min, max),
+                        cpal::SupportedBufferSize::Unknown => "buffer size unknown".to_string(),
+                    };
+                    println!("        {} channels, {}..{} Hz, {}, {}",
+                        range.channels(), range.min_sample_rate().0, range.max_sample_rate().0, buffer, range.sample_format());
+                },
+                Err(e) => println!("        {}", e)
+            }
+        }
+        let default_input = host.default_input_device().map(|device| device_name(&device));
+        if let Ok(devices) = host.input_devices() {
+            for (device_idx, device) in devices.enumerate() {
+                let name = device_name(&device);
+                println!("    Input device {}: {}{}", device_idx, name, if Some(&name) == default_input.as_ref() { " (default)" } else { "" });
+            }
+        }
     }
 }
-impl From<cpal::BuildStreamError> for CpalError { fn from(e: cpal::BuildStreamError) -> Self { CpalError::Build(e) } }
-impl From<cpal::PlayStreamError> for CpalError { fn from(e: cpal::PlayStreamError) -> Self { CpalError::Play(e) } }
 
-fn audio_run<T>(device: &cpal::Device, config: &cpal::StreamConfig, audio_chunk_recv: crossbeam_channel::Receiver<Box<AudioChunk>>) -> Result<cpal::Stream, CpalError>
+// Build the synthesizer and the output stage for a given sample rate.
+// One voice per scan head, each taking frames from its own receiver in audio_frame_recvs.
+// Voices synthesize at settings.synth.sample_rate; sample_rate is the device's, which everything after the mixer runs at.
+fn audio_chain(sample_rate: f32, bits: Option<u32>, settings: &Settings, audio_stats: Arc<AudioStats>, audio_controls: Arc<AudioControls>, audio_frame_recvs: &[crossbeam_channel::Receiver<Box<AudioFrame>>], frame_pool: &FramePool) -> Result<(Mixer, OutputStage), AudioError> {
+    let synth_rate = settings.synth.sample_rate as f32;
+    let mut voices = Vec::new();
+    for (head, audio_frame_recv) in settings.heads().iter().zip(audio_frame_recvs) {
+        let engine = match settings.synth.mode {
+            SynthMode::Spectral => SynthEngine::Spectral,
+            SynthMode::Wavetable => SynthEngine::Wavetable(Box::new(Wavetable::new(&settings.synth.wavetable, synth_rate, settings.synth.bins(), settings.synth.hop()))),
+            SynthMode::Granular => {
+                let config = &settings.synth.granular;
+                let granular = Granular::new(config, synth_rate, settings.synth.bins(), head.curve)
+                    .map_err(|e| AudioError::GrainFile(config.source.clone().unwrap_or_default(), e))?;
+                SynthEngine::Granular(Box::new(granular))
+            },
+        };
+        let resynth = Resynth::new(synth_rate, settings.synth.clone(), audio_stats.clone(), audio_controls.clone(), audio_frame_recv.clone(), frame_pool.clone(), engine);
+        voices.push(Voice { resynth, gains: head.channel_gains() });
+    }
+    if let (SynthMode::Granular, Some(path)) = (settings.synth.mode, &settings.synth.granular.source) {
+        println!("Taking grains from {}", path.display());
+    }
+    let mixer = Mixer::new(voices, &settings.synth, sample_rate);
+    if sample_rate != synth_rate {
+        println!("Resampling from {} Hz to {} Hz", synth_rate, sample_rate);
+    }
+
+    let stage = OutputStage::new(&settings.output, &settings.effects, audio_controls, sample_rate, bits);
+    Ok((mixer, stage))
+}
+
+// Except on the web, the synthesizer gets a thread of its own. buffer_frames is the most the output asks for at once.
+fn synth_source(mixer: Mixer, audio_stats: Arc<AudioStats>, sample_rate: f32, buffer_frames: usize) -> (SynthSource, Option<WorkerThread>) {
+    if cfg!(target_arch = "wasm32") {
+        (SynthSource::Inline(Box::new(mixer)), None)
+    } else {
+        let (source, thread) = synth_spawn(mixer, audio_stats, sample_rate, buffer_frames);
+        (source, Some(thread))
+    }
+}
+
+// Channels files are written with: stereo only if some scan head is off center, so the left and right differ
+pub fn output_channels(settings: &Settings) -> u16 {
+    if settings.heads().iter().any(|head| head.pan != 0.) { 2 } else { 1 }
+}
+
+fn audio_run<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut source: SynthSource, mut stage: OutputStage, mut recorder: Option<RecorderInput>) -> Result<cpal::Stream, AudioError>
 where
-    T: SizedSample + FromSample<f32> + bytemuck::Pod, /* Pod constraint can be removed without audio_log */
+    T: SizedSample + FromSample<f32>,
 {
-//    let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
 
-    // Produce a sinusoid of maximum amplitude.
-//    let mut sample_clock = 0f32;
-
-    // AUDIO STATE HERE
-    // One box is "current", the other is "previous".
-    let mut chunks:[Box<AudioChunk>;2] = [Box::new(std::array::from_fn(|_|0.)), Box::new(std::array::from_fn(|_|0.))];
-    let mut box_idx = 1;
-    let mut sample_idx = AUDIO_CHUNK_LEN;
-    let mut transitioning = true;
-    let trail_by = AUDIO_CHUNK_LEN/2;
-
     let mut next_value = move || {
         // -- SYNTHESIS HERE --
-        if sample_idx >= AUDIO_CHUNK_LEN {
-            if let Ok(incoming_chunk) = audio_chunk_recv.try_recv() {
-                box_idx = (box_idx + 1) % 2;
-                chunks[box_idx] = incoming_chunk;
-                transitioning = true;
-            } else {
-                transitioning = false;
-            }
-            sample_idx = 0;
-        }
-//        println!("{}:{}, {}, {}", box_idx, sample_idx, transitioning, if transitioning { (box_idx+1)%2 } else {box_idx});
-        // Chunks from the graphics thread are pre-windowed and pre-divided by two so we just need to sum them
-        let out
-          = chunks[
-                if transitioning {
-                    (box_idx+1)%2
-                } else {box_idx}
-            ][
-                (sample_idx+trail_by)%AUDIO_CHUNK_LEN
-            ]
-          + chunks[box_idx][sample_idx];
-        sample_idx += 1;
-        out
+        source.next_frame()
         // -- BOILERPLATE --
     };
 
     let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
-    #[cfg(feature = "audio_log")]
-    let mut audio_log = std::fs::File::create("audio_log.raw").unwrap();
-    #[cfg(not(feature = "audio_log"))]
-    let mut audio_log:AudioLog = ();
-
     let stream = device.build_output_stream(
         config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            audio_write(data, channels, &mut next_value, &mut audio_log)
+            realtime(|| audio_write(data, channels, &mut next_value, &mut stage, &mut recorder))
         },
         err_fn,
         None,
@@ -118,41 +650,126 @@ where
     Ok(stream)
 }
 
-pub fn audio_spawn(audio_chunk_recv: crossbeam_channel::Receiver<Box<AudioChunk>>) -> Option<cpal::Stream> {
-    let host = cpal::default_host();
-    if let Some(device) = host.default_output_device() {
-        let config = device.default_output_config().unwrap();
-
-        let stream_result = match config.sample_format() {
-            cpal::SampleFormat::I8 => audio_run::<i8>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::I16 => audio_run::<i16>(&device, &config.into(), audio_chunk_recv),
-            // cpal::SampleFormat::I24 => audio_run::<I24>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::I32 => audio_run::<i32>(&device, &config.into(), audio_chunk_recv),
-            // cpal::SampleFormat::I48 => audio_run::<I48>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::I64 => audio_run::<i64>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::U8 => audio_run::<u8>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::U16 => audio_run::<u16>(&device, &config.into(), audio_chunk_recv),
-            // cpal::SampleFormat::U24 => audio_run::<U24>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::U32 => audio_run::<u32>(&device, &config.into(), audio_chunk_recv),
-            // cpal::SampleFormat::U48 => audio_run::<U48>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::U64 => audio_run::<u64>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::F32 => audio_run::<f32>(&device, &config.into(), audio_chunk_recv),
-            cpal::SampleFormat::F64 => audio_run::<f64>(&device, &config.into(), audio_chunk_recv),
-            sample_format => panic!("Unsupported sample format '{sample_format}'"),
-        };
+// Stand-in for a device callback: synthesize in real time on a thread of our own and hand each block
+// (of channels interleaved channels, 1 or 2) to sink
+fn sink_spawn(sample_rate: u32, channels: u16, mut source: SynthSource, mut stage: OutputStage, mut recorder: Option<RecorderInput>, mut sink: impl FnMut(&[f32]) -> std::io::Result<()> + Send + 'static) -> WorkerThread {
+    WorkerThread::spawn("audio sink", move |quit| {
+        let mut block = [0f32; AUDIO_SINK_BLOCK_LEN*2];
+        let block = &mut block[..AUDIO_SINK_BLOCK_LEN*channels as usize];
+        let start = Instant::now();
+        let mut written:u64 = 0;
+        while !quit.load(Ordering::Relaxed) {
+            realtime(|| audio_write(block, channels as usize, &mut || source.next_frame(), &mut stage, &mut recorder));
+            if let Err(e) = sink(block) {
+                println!("Audio sink failed: {}", e);
+                break;
+            }
+            written += AUDIO_SINK_BLOCK_LEN as u64;
 
-        match stream_result {
-            Err(e) => {
-                println!("Failure: {}", e);
-                None
-            },
-            Ok(v) => {
-                println!("Boot");
-                Some(v)
+            // Don't get ahead of the wall clock
+            let due = start + Duration::from_secs_f64(written as f64 / sample_rate as f64);
+            let now = Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
             }
         }
-    } else {
-        println!("Failure: No device");
-        None
+    })
+}
+
+// Everything that has to be kept alive for sound to keep playing
+pub struct AudioOutput {
+    _stream: Option<cpal::Stream>,
+    _sink: Option<WorkerThread>,
+    _synth: Option<WorkerThread>,
+    _recorder: Option<WorkerThread>,
+}
+
+// Backend is passed separately from settings so a caller can fall back to another one
+// audio_frame_recvs has one receiver per scan head; played frames go back to frame_pool
+pub fn audio_spawn(backend: &AudioBackend, settings: &Settings, audio_stats: Arc<AudioStats>, audio_controls: Arc<AudioControls>, audio_frame_recvs: &[crossbeam_channel::Receiver<Box<AudioFrame>>], frame_pool: &FramePool) -> Result<AudioOutput, AudioError> {
+    if *backend == AudioBackend::Cpal {
+        return cpal_spawn(settings, audio_stats, audio_controls, audio_frame_recvs, frame_pool);
     }
+
+    // The other backends need threads and (for File) files, which aren't available on the web
+    if cfg!(target_arch = "wasm32") {
+        return Err(AudioError::BackendUnavailable);
+    }
+
+    let sample_rate = settings.audio_device.sample_rate.unwrap_or(AUDIO_SINK_SAMPLE_RATE);
+    let bits = output_bits(None, settings.output.bits);
+    let format = bits.map_or(WavFormat::F32, WavFormat::from_bits);
+    let channels = output_channels(settings);
+    let (mixer, stage) = audio_chain(sample_rate as f32, bits, settings, audio_stats.clone(), audio_controls.clone(), audio_frame_recvs, frame_pool)?;
+    let (source, synth_thread) = synth_source(mixer, audio_stats, sample_rate as f32, AUDIO_SINK_BLOCK_LEN);
+    let (recorder, recorder_thread) = recorder_spawn(settings.record.clone(), audio_controls.clone(), format, settings.output.dither, channels, sample_rate);
+
+    let sink_thread = match backend {
+        AudioBackend::File(path) => {
+            // The output stage has already dithered to the file's depth
+            let mut writer = WavWriter::create(path, format, channels, sample_rate, DitherMode::None)?;
+            println!("Writing audio to {}", path.display());
+            let mut patched_at = 0.;
+            sink_spawn(sample_rate, channels, source, stage, Some(recorder), move |block| {
+                writer.write_samples(block)?;
+                // Keep the header current once a second, so the file is readable even if we never exit cleanly
+                if writer.duration() - patched_at >= 1. {
+                    writer.patch_header()?;
+                    patched_at = writer.duration();
+                }
+                Ok(())
+            })
+        },
+        _ => sink_spawn(sample_rate, channels, source, stage, Some(recorder), |_| Ok(())),
+    };
+
+    Ok(AudioOutput { _stream: None, _sink: Some(sink_thread), _synth: synth_thread, _recorder: Some(recorder_thread) })
+}
+
+fn cpal_spawn(settings: &Settings, audio_stats: Arc<AudioStats>, audio_controls: Arc<AudioControls>, audio_frame_recvs: &[crossbeam_channel::Receiver<Box<AudioFrame>>], frame_pool: &FramePool) -> Result<AudioOutput, AudioError> {
+    let device_config = &settings.audio_device;
+    let host = choose_host(&device_config.host)?;
+    let device = choose_device(&host, &device_config.device)?;
+    let (sample_format, config) = choose_config(&device, device_config)?;
+    let bits = output_bits(format_bits(sample_format), settings.output.bits);
+    if let (Some(bits), true) = (bits, settings.output.dither != DitherMode::None) {
+        println!("Dithering output to {} bits", bits);
+    }
+    let (mixer, stage) = audio_chain(config.sample_rate.0 as f32, bits, settings, audio_stats.clone(), audio_controls.clone(), audio_frame_recvs, frame_pool)?;
+    let buffer_frames = match config.buffer_size {
+        cpal::BufferSize::Fixed(size) => size as usize,
+        cpal::BufferSize::Default => AUDIO_DEVICE_BUFFER_GUESS,
+    };
+    let (source, synth_thread) = synth_source(mixer, audio_stats, config.sample_rate.0 as f32, buffer_frames);
+
+    // Threads and files aren't available on the web
+    let (recorder, recorder_thread) = if cfg!(target_arch = "wasm32") {
+        (None, None)
+    } else {
+        let format = bits.map_or(WavFormat::from_sample_format(sample_format), WavFormat::from_bits);
+        let (input, thread) = recorder_spawn(settings.record.clone(), audio_controls, format, settings.output.dither, output_channels(settings), config.sample_rate.0);
+        (Some(input), Some(thread))
+    };
+
+    let stream = match sample_format {
+        cpal::SampleFormat::I8 => audio_run::<i8>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::I16 => audio_run::<i16>(&device, &config, source, stage, recorder),
+        // cpal 0.15 has no SampleFormat for packed 24 or 48 bit samples; 24-bit devices show up as I32, and --bits 24 dithers to suit.
+        // choose_config only picks formats from this list.
+        // cpal::SampleFormat::I24 => audio_run::<I24>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::I32 => audio_run::<i32>(&device, &config, source, stage, recorder),
+        // cpal::SampleFormat::I48 => audio_run::<I48>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::I64 => audio_run::<i64>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::U8 => audio_run::<u8>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::U16 => audio_run::<u16>(&device, &config, source, stage, recorder),
+        // cpal::SampleFormat::U24 => audio_run::<U24>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::U32 => audio_run::<u32>(&device, &config, source, stage, recorder),
+        // cpal::SampleFormat::U48 => audio_run::<U48>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::U64 => audio_run::<u64>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::F32 => audio_run::<f32>(&device, &config, source, stage, recorder),
+        cpal::SampleFormat::F64 => audio_run::<f64>(&device, &config, source, stage, recorder),
+        sample_format => Err(AudioError::UnsupportedFormat(sample_format)),
+    }?;
+
+    Ok(AudioOutput { _stream: Some(stream), _sink: None, _synth: synth_thread, _recorder: recorder_thread })
 }
@@ -51,6 +51,10 @@ pub fn make_sampler(device: &wgpu::Device) -> wgpu::Sampler { // Currently unuse
 }
 
 pub fn make_texture_gray(device: &wgpu::Device, width:u32, height:u32, target:bool, source:bool, label:&str) -> (wgpu::Texture, wgpu::TextureView) {
+    make_texture(device, width, height, wgpu::TextureFormat::R8Unorm, target, source, label)
+}
+
+pub fn make_texture(device: &wgpu::Device, width:u32, height:u32, format:wgpu::TextureFormat, target:bool, source:bool, label:&str) -> (wgpu::Texture, wgpu::TextureView) {
 	let mut usage = wgpu::TextureUsages::TEXTURE_BINDING.union(wgpu::TextureUsages::COPY_DST);
     if target { usage = usage | wgpu::TextureUsages::TEXTURE_BINDING.union(wgpu::TextureUsages::RENDER_ATTACHMENT) };
     if source { usage = usage | wgpu::TextureUsages::TEXTURE_BINDING.union(wgpu::TextureUsages::COPY_SRC) };
@@ -60,7 +64,7 @@ pub fn make_texture_gray(device: &wgpu::Device, width:u32, height:u32, target:bo
 	    mip_level_count: 1,
 	    sample_count: 1,
 	    dimension: wgpu::TextureDimension::D2,
-	    format: wgpu::TextureFormat::R8Unorm,
+	    format,
 	    usage: usage,
 	    label: Some(label),
 	    view_formats: &[],
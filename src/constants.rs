@@ -2,13 +2,56 @@
 
 pub const DEFAULT_STROKE:f32 = 0.1;
 
+// Stroke widths the diagonal can be drawn at; past half a tile its two edges cross
+pub const STROKE_MIN:f32 = 0.01;
+pub const STROKE_MAX:f32 = 0.5;
+
 pub const TILES_ACROSS:u32 = 5;
 
+// Most tiles across the window can be asked for. The grid is drawn from about 4*tiles^2 quads, times the aspect ratio.
+pub const TILES_MAX:u32 = 200;
+
 pub const GRID_ANIMATE_SPEED:f32 = 1.5;
 
-// GPUImageCopyBuffer requires this to be a multiple of 256
-pub const AUDIO_READBACK_BUFFER_LEN:usize = 1024;
+// Slowest the grid can scroll, in row shifts per second. It can't stop outright, since MIDI export times notes by it.
+pub const GRID_ANIMATE_SPEED_MIN:f32 = 0.01;
+
+// Blur passes run, unless --blur-passes says otherwise, and the most that can be asked for
+pub const TARGET_PASSES:usize = 8;
+pub const BLUR_PASSES_MAX:usize = 32;
+
+// Distance in pixels the first pair of blur passes samples at, and how much further each pair after reaches
+pub const BLUR_RADIUS:f32 = 1.;
+pub const BLUR_GROWTH:f32 = 2.;
+
+// Tile side, in pixels, that the blur radius is given for: a default 800x600 window of 5 tiles. The blur is scaled to
+// the tiles actually drawn, so the threshold finds the same blobs whatever the window size, tile count or DPI.
+pub const BLUR_REFERENCE_TILE:f32 = 120.;
+
+// Least gap between the threshold's low and high edges; smoothstep is undefined unless low is below high
+pub const THRESHOLD_EDGE_MIN:f32 = 0.001;
+
+// FFT size audio is synthesized with, unless --fft-size says otherwise. The readback row is half this long,
+// one pixel per frequency bin.
+pub const AUDIO_CHUNK_LEN:usize = 2048;
+
+// Range --fft-size can be set within. Sizes must be powers of two.
+pub const AUDIO_CHUNK_LEN_MIN:usize = 256;
+pub const AUDIO_CHUNK_LEN_MAX:usize = 16384;
+
+// Rate the synthesizer runs at, unless --synth-rate says otherwise. Its output is resampled to whatever the device
+// plays at, so a bin sounds at the same frequency on any device.
+pub const AUDIO_SYNTH_RATE:u32 = 48000;
+
+// Audio thread resynthesizes one chunk every chunk/this many samples, unless --overlap or --hop say otherwise
+pub const AUDIO_OVERLAP:usize = 2;
+
+// Range master volume is held to, in dB, however it's set: --volume, the + and - keys, or OSC
+pub const VOLUME_DB_MIN:f32 = -120.;
+pub const VOLUME_DB_MAX:f32 = 24.;
 
-pub const AUDIO_CHUNK_LEN:usize = AUDIO_READBACK_BUFFER_LEN*2;
+// Steepness of the exponential warp a scan head is read from the screen with, unless it says otherwise (see scan.rs)
+pub const READBACK_CURVE:f32 = 20.;
 
-pub type AudioChunk = [f32;AUDIO_CHUNK_LEN];
+// Width in pixels of the lines --markers draws scan heads with
+pub const SCAN_MARKER_WIDTH:f32 = 3.;
@@ -0,0 +1,99 @@
+// Live parameter changes, and the look they change
+
+use crate::constants::*;
+
+// The picture's adjustable parameters. Starts out from settings; Controls change it while running.
+#[derive(Clone, Debug)]
+pub struct VisualConfig {
+    pub speed: f32,            // Row shifts per second
+    pub stroke: f32,           // Width of the diagonal, as a fraction of a tile
+    pub tiles: u32,            // Tiles across the shorter side of the window
+    pub seed: Option<u64>,     // Seed for tile flips; None to be different every run
+    pub threshold: (f32, f32), // Blurred values between these become the soft edge of a blob
+    pub threshold_mode: ThresholdMode,
+    pub threshold_invert: bool, // Blobs dark on light instead of light on dark
+    pub blur_passes: usize,    // 1 to BLUR_PASSES_MAX; the last one also thresholds
+    pub blur_radius: f32,      // Pixels the first pair of passes samples at
+    pub blur_growth: f32,      // Factor each pair of passes reaches further than the last
+    pub tap: AudioTap,         // Which stage of the picture the audio hears
+}
+
+impl Default for VisualConfig {
+    fn default() -> Self {
+        VisualConfig {
+            speed: GRID_ANIMATE_SPEED,
+            stroke: DEFAULT_STROKE,
+            tiles: TILES_ACROSS,
+            seed: None,
+            threshold: (0.6, 0.7), // .4, .5 are also interesting
+            threshold_mode: ThresholdMode::Soft,
+            threshold_invert: false,
+            blur_passes: TARGET_PASSES,
+            blur_radius: BLUR_RADIUS,
+            blur_growth: BLUR_GROWTH,
+            tap: AudioTap::Final,
+        }
+    }
+}
+
+impl VisualConfig {
+    // Pixels a blur pass samples at. Passes go in pairs, X then Y, and each pair reaches further than the last.
+    pub fn blur_step(&self, stage: usize) -> f32 {
+        self.blur_radius * self.blur_growth.powi((stage/2) as i32)
+    }
+
+    // The threshold half of a blur pass's uniform: edges, invert, hard, as BlurParams in shader.wgsl has them
+    pub fn threshold_uniform(&self) -> [u32; 4] {
+        let (low, high) = self.threshold;
+        [low.to_bits(), high.to_bits(), self.threshold_invert as u32, (self.threshold_mode == ThresholdMode::Hard) as u32]
+    }
+}
+
+// How the final pass turns blurred levels into blobs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThresholdMode {
+    Soft, // Ramp smoothly from the low edge to the high
+    Hard, // Cut off halfway between them
+}
+
+// Where along the postprocess chain the readback row is taken from. Each sounds quite different:
+// the grid is hard-edged and buzzy, blur softens it, and the threshold brings the edges back around the blobs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioTap {
+    Grid,         // The tiles as drawn, before any blur
+    Blur(usize),  // After this many blur passes (no more than the last one before thresholding)
+    Final,        // After the last blur pass before thresholding
+    Threshold,    // The thresholded picture, as on screen
+}
+
+impl AudioTap {
+    // Blur passes run before the row is read back, given how many passes there are in all; None to read after thresholding
+    pub fn stage(self, blur_passes: usize) -> Option<usize> {
+        let last = blur_passes.saturating_sub(1);
+        match self {
+            AudioTap::Grid => Some(0),
+            AudioTap::Blur(passes) => Some(passes.min(last)),
+            AudioTap::Final => Some(last),
+            AudioTap::Threshold => None,
+        }
+    }
+}
+
+// A change requested from outside the render loop (see osc.rs), applied at the start of the next frame
+#[derive(Clone, Debug, PartialEq)]
+pub enum Control {
+    Speed(f32),
+    Stroke(f32),
+    Tiles(u32),
+    Seed(u64),
+    Threshold(f32, f32),
+    ThresholdMode(ThresholdMode),
+    ThresholdInvert(bool),
+    BlurPasses(usize),
+    BlurRadius(f32),
+    BlurGrowth(f32),
+    Tap(AudioTap),
+    Volume(f32), // dB
+    Mute(bool),
+    Record(bool),
+}
@@ -0,0 +1,52 @@
+// Dither for turning float samples into integers, so quiet passages fade into noise instead of distortion
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DitherMode {
+    None,   // Plain rounding
+    #[default]
+    Tpdf,   // Triangular noise of one step either way, which makes the rounding error independent of the signal
+    Shaped, // Tpdf, with the error pushed up toward frequencies the ear is less sensitive to
+}
+
+// Error feedback filter for Shaped: a 3-tap F-weighted curve (after Wannamaker), designed for 44.1 kHz but fine nearby
+const DITHER_SHAPING:[f64; 3] = [1.623, -0.982, 0.109];
+
+// Rounds one stream of samples to integer steps. Keeps state, so use one per stream.
+pub struct Dither {
+    mode: DitherMode,
+    rng: StdRng,
+    error: [f64; 3], // Most recent quantization errors, newest first
+}
+
+impl Dither {
+    pub fn new(mode: DitherMode) -> Self {
+        Dither { mode, rng: StdRng::from_entropy(), error: [0.; 3] }
+    }
+
+    // Scale a -1..1 sample up by scale and round it to a whole number, clamped to -scale..=scale
+    pub fn quantize(&mut self, sample: f32, scale: f64) -> f64 {
+        let mut wanted = sample as f64 * scale;
+        if self.mode == DitherMode::Shaped {
+            wanted -= self.error.iter().zip(DITHER_SHAPING.iter()).map(|(error, coeff)| error*coeff).sum::<f64>();
+        }
+        let noise = match self.mode {
+            DitherMode::None => 0.,
+            _ => self.rng.gen::<f64>() - self.rng.gen::<f64>(),
+        };
+        let value = (wanted + noise).round().clamp(-scale, scale);
+        if self.mode == DitherMode::Shaped {
+            // Clamp the error too, so clipping can't wind up the feedback
+            self.error = [(value - wanted).clamp(-2., 2.), self.error[0], self.error[1]];
+        }
+        value
+    }
+
+    // Reduce a -1..1 sample to the given bit depth, staying in -1..1. The result converts exactly to any integer
+    // format at least that wide.
+    pub fn reduce(&mut self, sample: f32, bits: u32) -> f32 {
+        let scale = (1u64 << (bits - 1)) as f64;
+        (self.quantize(sample, scale).min(scale - 1.) / scale) as f32
+    }
+}
@@ -0,0 +1,301 @@
+// Effects that can be chained after the synthesizer, to turn raw spectral noise into something more finished
+
+use std::f32::consts::PI;
+
+// Longest delay any effect can be given, in seconds. Delay lines are allocated up front, so this bounds their memory.
+pub const EFFECT_TIME_MAX:f32 = 10.;
+
+// Anything that transforms a block of mono samples in place. Runs on the audio thread, so process() must not block
+// or allocate; set up any buffers in the constructor.
+pub trait AudioEffect: Send {
+    fn process(&mut self, block: &mut [f32]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DelayConfig {
+    pub time: f32,     // Seconds
+    pub feedback: f32, // 0..1, how much of each echo goes round again
+    pub damping: f32,  // 0..1, how much duller each echo gets
+    pub mix: f32,      // 0 dry .. 1 wet
+}
+
+impl Default for DelayConfig {
+    fn default() -> Self { DelayConfig { time: 0.35, feedback: 0.4, damping: 0.3, mix: 0.3 } }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReverbConfig {
+    pub size: f32,    // 0..1, longer tail as it goes up
+    pub damping: f32, // 0..1, how quickly highs die away
+    pub mix: f32,
+}
+
+impl Default for ReverbConfig {
+    fn default() -> Self { ReverbConfig { size: 0.7, damping: 0.4, mix: 0.3 } }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterConfig {
+    pub mode: FilterMode,
+    pub cutoff: f32, // Hz
+    pub q: f32,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self { FilterConfig { mode: FilterMode::Lowpass, cutoff: 2000., q: 0.707 } }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChorusConfig {
+    pub rate: f32,  // Hz
+    pub depth: f32, // Seconds either side of delay
+    pub delay: f32, // Seconds
+    pub mix: f32,
+}
+
+impl Default for ChorusConfig {
+    fn default() -> Self { ChorusConfig { rate: 0.8, depth: 0.003, delay: 0.015, mix: 0.5 } }
+}
+
+// One link in the effects chain, as it appears in settings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EffectConfig {
+    Delay(DelayConfig),
+    Reverb(ReverbConfig),
+    Filter(FilterConfig),
+    Chorus(ChorusConfig),
+}
+
+impl EffectConfig {
+    pub fn build(&self, sample_rate: f32) -> Box<dyn AudioEffect> {
+        match *self {
+            EffectConfig::Delay(config) => Box::new(FeedbackDelay::new(config, sample_rate)),
+            EffectConfig::Reverb(config) => Box::new(Reverb::new(config, sample_rate)),
+            EffectConfig::Filter(config) => Box::new(StateVariableFilter::new(config, sample_rate)),
+            EffectConfig::Chorus(config) => Box::new(Chorus::new(config, sample_rate)),
+        }
+    }
+}
+
+// Effects applied one after another, in the order given
+pub struct EffectChain {
+    effects: Vec<Box<dyn AudioEffect>>,
+}
+
+impl EffectChain {
+    pub fn new(configs: &[EffectConfig], sample_rate: f32) -> Self {
+        EffectChain { effects: configs.iter().map(|config| config.build(sample_rate)).collect() }
+    }
+}
+
+impl AudioEffect for EffectChain {
+    fn process(&mut self, block: &mut [f32]) {
+        for effect in self.effects.iter_mut() {
+            effect.process(block);
+        }
+    }
+}
+
+// Ring buffer holding the last len samples
+struct DelayLine {
+    buffer: Vec<f32>,
+    idx: usize, // Next write position, which is also the oldest sample
+}
+
+impl DelayLine {
+    fn new(len: usize) -> Self {
+        DelayLine { buffer: vec![0.; len.max(1)], idx: 0 }
+    }
+
+    // The sample written len samples ago
+    fn read(&self) -> f32 {
+        self.buffer[self.idx]
+    }
+
+    // The sample written delay samples ago, interpolated. delay is clamped to 1..=len
+    fn read_at(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let pos = self.idx as f32 + len as f32 - delay.clamp(1., len as f32);
+        let (base, frac) = (pos.floor() as usize, pos.fract());
+        self.buffer[base % len]*(1.-frac) + self.buffer[(base+1) % len]*frac
+    }
+
+    fn write(&mut self, sample: f32) {
+        self.buffer[self.idx] = sample;
+        self.idx = (self.idx + 1) % self.buffer.len();
+    }
+}
+
+fn mix(dry: f32, wet: f32, mix: f32) -> f32 {
+    dry*(1.-mix) + wet*mix
+}
+
+// Echoes that get quieter and duller each time round
+pub struct FeedbackDelay {
+    config: DelayConfig,
+    line: DelayLine,
+    lowpass: f32,
+}
+
+impl FeedbackDelay {
+    pub fn new(config: DelayConfig, sample_rate: f32) -> Self {
+        FeedbackDelay { config, line: DelayLine::new((config.time*sample_rate).round() as usize), lowpass: 0. }
+    }
+}
+
+impl AudioEffect for FeedbackDelay {
+    fn process(&mut self, block: &mut [f32]) {
+        let DelayConfig { feedback, damping, mix: wet, .. } = self.config;
+        for sample in block.iter_mut() {
+            let echo = self.line.read();
+            self.lowpass = echo*(1.-damping) + self.lowpass*damping;
+            self.line.write(*sample + self.lowpass*feedback);
+            *sample = mix(*sample, echo, wet);
+        }
+    }
+}
+
+// Lowpass-damped feedback comb, as in Freeverb
+struct Comb {
+    line: DelayLine,
+    lowpass: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let out = self.line.read();
+        self.lowpass = out*(1.-damping) + self.lowpass*damping;
+        self.line.write(input + self.lowpass*feedback);
+        out
+    }
+}
+
+struct Allpass {
+    line: DelayLine,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line.read();
+        self.line.write(input + delayed*0.5);
+        delayed - input
+    }
+}
+
+// Delay lengths in samples at 44.1 kHz, from Freeverb. Mutually prime-ish, so the echoes don't pile up.
+const REVERB_COMB_TUNING:[usize; 4] = [1116, 1188, 1277, 1356];
+const REVERB_ALLPASS_TUNING:[usize; 2] = [556, 441];
+
+// Schroeder reverb: parallel combs for the decaying tail, then allpasses in series to smear it out
+pub struct Reverb {
+    config: ReverbConfig,
+    feedback: f32,
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Reverb {
+    pub fn new(config: ReverbConfig, sample_rate: f32) -> Self {
+        let scale = sample_rate / 44100.;
+        let scaled = |len: usize| (len as f32 * scale).round() as usize;
+        Reverb {
+            config,
+            feedback: 0.7 + config.size.clamp(0., 1.)*0.28,
+            combs: REVERB_COMB_TUNING.iter().map(|&len| Comb { line: DelayLine::new(scaled(len)), lowpass: 0. }).collect(),
+            allpasses: REVERB_ALLPASS_TUNING.iter().map(|&len| Allpass { line: DelayLine::new(scaled(len)) }).collect(),
+        }
+    }
+}
+
+impl AudioEffect for Reverb {
+    fn process(&mut self, block: &mut [f32]) {
+        let input_gain = 1. / self.combs.len() as f32;
+        for sample in block.iter_mut() {
+            let input = *sample * input_gain;
+            let mut wet:f32 = self.combs.iter_mut().map(|comb| comb.process(input, self.feedback, self.config.damping)).sum();
+            for allpass in self.allpasses.iter_mut() {
+                wet = allpass.process(wet);
+            }
+            *sample = mix(*sample, wet, self.config.mix);
+        }
+    }
+}
+
+// Trapezoidal state-variable filter (after Andrew Simper), which stays stable while its settings change
+pub struct StateVariableFilter {
+    mode: FilterMode,
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl StateVariableFilter {
+    pub fn new(config: FilterConfig, sample_rate: f32) -> Self {
+        let cutoff = config.cutoff.clamp(1., sample_rate*0.49);
+        let g = (PI * cutoff / sample_rate).tan();
+        let k = 1. / config.q.max(0.01);
+        let a1 = 1. / (1. + g*(g + k));
+        let a2 = g*a1;
+        StateVariableFilter { mode: config.mode, k, a1, a2, a3: g*a2, ic1eq: 0., ic2eq: 0. }
+    }
+}
+
+impl AudioEffect for StateVariableFilter {
+    fn process(&mut self, block: &mut [f32]) {
+        for sample in block.iter_mut() {
+            let v3 = *sample - self.ic2eq;
+            let v1 = self.a1*self.ic1eq + self.a2*v3;
+            let v2 = self.ic2eq + self.a2*self.ic1eq + self.a3*v3;
+            self.ic1eq = 2.*v1 - self.ic1eq;
+            self.ic2eq = 2.*v2 - self.ic2eq;
+
+            let (low, band) = (v2, v1);
+            let high = *sample - self.k*band - low;
+            *sample = match self.mode {
+                FilterMode::Lowpass => low,
+                FilterMode::Highpass => high,
+                FilterMode::Bandpass => band,
+                FilterMode::Notch => low + high,
+            };
+        }
+    }
+}
+
+// A copy of the signal on a slowly wobbling delay, mixed back in
+pub struct Chorus {
+    config: ChorusConfig,
+    line: DelayLine,
+    phase: f32, // LFO, 0..1
+    phase_step: f32,
+    sample_rate: f32,
+}
+
+impl Chorus {
+    pub fn new(config: ChorusConfig, sample_rate: f32) -> Self {
+        let longest = ((config.delay + config.depth.abs())*sample_rate).ceil() as usize + 2;
+        Chorus { config, line: DelayLine::new(longest), phase: 0., phase_step: config.rate / sample_rate, sample_rate }
+    }
+}
+
+impl AudioEffect for Chorus {
+    fn process(&mut self, block: &mut [f32]) {
+        for sample in block.iter_mut() {
+            let delay = (self.config.delay + self.config.depth*(2.*PI*self.phase).sin()) * self.sample_rate;
+            let wet = self.line.read_at(delay);
+            self.line.write(*sample);
+            self.phase = (self.phase + self.phase_step).fract();
+            *sample = mix(*sample, wet, self.config.mix);
+        }
+    }
+}
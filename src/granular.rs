@@ -0,0 +1,157 @@
+// Granular synthesis: short windowed snippets of a sound, scattered according to the readback row
+
+use std::path::PathBuf;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::scan::readback_warp;
+use crate::wav::wav_read;
+
+#[derive(Clone, Debug)]
+pub struct GranularConfig {
+    pub source: Option<PathBuf>, // WAV file to take grains from; None to take them from the last few seconds of spectral output
+    pub grain_length: f32,       // Seconds
+    pub density: f32,            // Grains per second with every bin at full brightness
+    pub pitch_range: f32,        // Semitones; the leftmost column plays this far down, the rightmost this far up
+    pub history: f32,            // Seconds of spectral output kept to take grains from, when there's no source file
+}
+
+impl Default for GranularConfig {
+    fn default() -> Self {
+        GranularConfig { source: None, grain_length: 0.08, density: 200., pitch_range: 12., history: 2. }
+    }
+}
+
+// Most grains sounding at once. Past this, new grains are skipped rather than allocated.
+const GRANULAR_MAX_GRAINS:usize = 256;
+
+// Where grains come from
+enum GrainSource {
+    File { samples: Vec<f32>, rate_ratio: f64 }, // rate_ratio is the file's sample rate over ours
+    Recent { samples: Vec<f32>, write_idx: usize }, // Ring of recent input to next_sample
+}
+
+struct Grain {
+    pos: f64,  // Index into the source, fractional
+    step: f64, // Source samples per output sample
+    age: usize,
+    amp: f64,
+}
+
+// Every hop, Resynth hands over the brightness of each bin. A column's brightness decides how likely grains are to
+// start there; where the column sits on screen (through the same X warp the readback shader reads with) decides
+// where in the source the grain comes from, and how far along the row it is decides the grain's pitch.
+pub struct Granular {
+    source: GrainSource,
+    grain_len: usize,
+    window: Vec<f64>,
+    density: f64,     // Grains per sample, at full brightness
+    pitch_range: f64, // Semitones
+    curve: f64,       // The scan head's, see readback_warp
+    levels: Vec<f64>, // Running total of bin brightness, for picking columns in proportion to it
+    rate: f64,        // Chance of a grain starting on any one sample, this hop
+    norm: f64,        // Keeps the level steady however many grains overlap
+    grains: Vec<Grain>,
+    rng: StdRng,
+}
+
+impl Granular {
+    // curve is the one the scan head driving it reads with
+    pub fn new(config: &GranularConfig, sample_rate: f32, bins: usize, curve: f32) -> std::io::Result<Granular> {
+        let source = match &config.source {
+            Some(path) => {
+                let (samples, file_rate) = wav_read(path)?;
+                if samples.is_empty() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "WAV file is empty"));
+                }
+                GrainSource::File { samples, rate_ratio: file_rate as f64 / sample_rate as f64 }
+            },
+            None => GrainSource::Recent { samples: vec![0.; ((config.history*sample_rate) as usize).max(1)], write_idx: 0 },
+        };
+        let grain_len = ((config.grain_length*sample_rate) as usize).max(2);
+        Ok(Granular {
+            source,
+            grain_len,
+            window: apodize::hanning_iter(grain_len).collect(),
+            density: config.density as f64 / sample_rate as f64,
+            pitch_range: config.pitch_range as f64,
+            curve: curve as f64,
+            levels: vec![0.; bins],
+            rate: 0.,
+            norm: 1.,
+            grains: Vec::with_capacity(GRANULAR_MAX_GRAINS),
+            rng: StdRng::from_entropy(),
+        })
+    }
+
+    // Whether grains come from the spectral output, which then has to keep being synthesized
+    pub fn wants_spectral(&self) -> bool {
+        matches!(self.source, GrainSource::Recent { .. })
+    }
+
+    // Take in the brightness (0..1) of every bin, for the hop about to be played
+    pub fn update(&mut self, level: impl Fn(usize) -> f64) {
+        let mut total = 0.;
+        for (idx, running) in self.levels.iter_mut().enumerate() {
+            total += level(idx).max(0.);
+            *running = total;
+        }
+        let brightness = total / self.levels.len() as f64;
+        self.rate = (self.density * brightness).min(1.);
+        let overlapping = self.rate * self.grain_len as f64;
+        self.norm = 1. / (1. + overlapping).sqrt();
+    }
+
+    fn start_grain(&mut self) {
+        let total = *self.levels.last().unwrap_or(&0.);
+        if total <= 0. || self.grains.len() >= GRANULAR_MAX_GRAINS { return }
+        let target = self.rng.gen::<f64>() * total;
+        let column = self.levels.partition_point(|&running| running < target).min(self.levels.len()-1);
+        let before = if column > 0 { self.levels[column-1] } else { 0. };
+        let amp = self.levels[column] - before;
+
+        let across = column as f64 / self.levels.len() as f64;
+        let position = readback_warp(across, self.curve);
+        let ratio = 2f64.powf(self.pitch_range * (2.*across - 1.) / 12.);
+
+        let (pos, step) = match &self.source {
+            GrainSource::File { samples, rate_ratio } => (position * samples.len() as f64, ratio * rate_ratio),
+            GrainSource::Recent { samples, write_idx } => {
+                // Position 1 is as recent as possible, while leaving room for the grain to play without reaching the write point
+                let len = samples.len() as f64;
+                let room = (self.grain_len as f64 * ratio).min(len);
+                let back = room + (1. - position) * (len - room);
+                ((*write_idx as f64 - back).rem_euclid(len), ratio)
+            },
+        };
+        self.grains.push(Grain { pos, step, age: 0, amp });
+    }
+
+    // One sample out. input is the spectral output, which is only used if grains are taken from it.
+    pub fn next_sample(&mut self, input: f32) -> f32 {
+        if let GrainSource::Recent { samples, write_idx } = &mut self.source {
+            samples[*write_idx] = input;
+            *write_idx = (*write_idx + 1) % samples.len();
+        }
+        if self.rate > 0. && self.rng.gen::<f64>() < self.rate {
+            self.start_grain();
+        }
+
+        let samples = match &self.source {
+            GrainSource::File { samples, .. } | GrainSource::Recent { samples, .. } => samples,
+        };
+        let len = samples.len();
+        let mut out = 0.;
+        for grain in self.grains.iter_mut() {
+            let base = grain.pos.floor();
+            let frac = grain.pos - base;
+            let idx = base as usize % len;
+            let sample = samples[idx] as f64*(1.-frac) + samples[(idx+1) % len] as f64*frac;
+            out += sample * self.window[grain.age] * grain.amp;
+            grain.pos = (grain.pos + grain.step) % len as f64;
+            grain.age += 1;
+        }
+        let grain_len = self.grain_len;
+        self.grains.retain(|grain| grain.age < grain_len);
+        (out * self.norm) as f32
+    }
+}
@@ -0,0 +1,303 @@
+// Audio-reactive mode: listen to a sound, and let it steer which way new tiles flip
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use web_time::{Duration, Instant};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, Sample, SizedSample,
+};
+use realfft::{RealToComplex, num_complex::Complex};
+
+use crate::audio::{AudioError, choose_by_name, choose_host, device_name};
+use crate::realtime::realtime;
+use crate::wav::wav_read;
+use crate::worker::WorkerThread;
+
+// What to listen to
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputSource {
+    File(PathBuf),          // A WAV file, analyzed in real time (but not played) over and over
+    Device(Option<String>), // A sound input device, by name or index; None for the default
+}
+
+#[derive(Clone, Debug)]
+pub struct InputConfig {
+    pub source: Option<InputSource>, // None to leave the flips purely random
+    pub amount: f32,                 // 0..1; 0 ignores the input, 1 lets it decide each flip outright
+    pub floor_db: f32,               // Band levels (dBFS) at or below this count as silence, and 0 dBFS as full
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        InputConfig { source: None, amount: 0.8, floor_db: -60. }
+    }
+}
+
+// Frequencies the columns span, evenly spaced in pitch, lowest on the left
+const INPUT_LOW_HZ:f32 = 40.;
+const INPUT_HIGH_HZ:f32 = 12000.;
+
+// Analysis window, and the samples between one analysis and the next. The same as the synthesizer's defaults,
+// though they don't have to match.
+const INPUT_FFT_LEN:usize = 2048;
+const INPUT_HOP_LEN:usize = INPUT_FFT_LEN/2;
+const INPUT_BINS:usize = INPUT_FFT_LEN/2;
+
+// Spectra analyzed but not yet picked up by the render loop. Past this, new ones are dropped.
+const INPUT_SPECTRA_MAX_INFLIGHT:usize = 64;
+
+// Spectra passed back and forth between the analyzer and the render loop, so the input callback never allocates or
+// frees one. All of them are made up front: enough to fill the channel, plus one being filled and one being read.
+#[derive(Clone)]
+struct SpectrumPool {
+    send: crossbeam_channel::Sender<Vec<f32>>,
+    recv: crossbeam_channel::Receiver<Vec<f32>>,
+}
+
+impl SpectrumPool {
+    fn new() -> Self {
+        let count = INPUT_SPECTRA_MAX_INFLIGHT + 2;
+        let (send, recv) = crossbeam_channel::bounded(count);
+        for _ in 0..count {
+            send.try_send(Vec::with_capacity(INPUT_BINS)).ok();
+        }
+        SpectrumPool { send, recv }
+    }
+
+    // A spectrum to fill, or None if they're all in use
+    fn take(&self) -> Option<Vec<f32>> {
+        self.recv.try_recv().ok()
+    }
+
+    // Done with a spectrum. Never blocks; there's room for every one there is.
+    fn give(&self, spectrum: Vec<f32>) {
+        self.send.try_send(spectrum).ok();
+    }
+}
+
+// Samples the file reader analyzes between checks of the clock
+const INPUT_FILE_BLOCK_LEN:usize = 512;
+
+// Forward transforms of the incoming signal: INPUT_FFT_LEN samples at a time, every INPUT_HOP_LEN samples.
+// Each one becomes a spectrum laid out like the ones the synthesizer plays, index idx being FFT bin idx+1,
+// but holding magnitudes where a full-scale sine reads 1.
+struct InputAnalyzer {
+    fft: Arc<dyn RealToComplex<f32>>,
+    fft_in: Vec<f32>,
+    fft_out: Vec<Complex<f32>>,
+    fft_scratch: Vec<Complex<f32>>,
+    window: Vec<f32>, // Includes the scale that makes a full-scale sine read 1
+    history: Vec<f32>, // Ring of the last INPUT_FFT_LEN samples
+    history_idx: usize, // Next write position, which is also the oldest sample
+    since_hop: usize,
+    spectrum_send: crossbeam_channel::Sender<Vec<f32>>,
+    spectrum_pool: SpectrumPool,
+}
+
+impl InputAnalyzer {
+    fn new(spectrum_send: crossbeam_channel::Sender<Vec<f32>>, spectrum_pool: SpectrumPool) -> Self {
+        let mut fft_planner = realfft::RealFftPlanner::<f32>::new();
+        let fft = fft_planner.plan_fft_forward(INPUT_FFT_LEN);
+        let (fft_in, fft_out, fft_scratch) = (fft.make_input_vec(), fft.make_output_vec(), fft.make_scratch_vec());
+
+        let mut window:Vec<f32> = apodize::hanning_iter(INPUT_FFT_LEN+1).take(INPUT_FFT_LEN).map(|x| x as f32).collect();
+        let scale = 2. / window.iter().sum::<f32>();
+        for sample in window.iter_mut() {
+            *sample *= scale;
+        }
+
+        InputAnalyzer {
+            fft, fft_in, fft_out, fft_scratch, window,
+            history: vec![0.; INPUT_FFT_LEN],
+            history_idx: 0,
+            since_hop: 0,
+            spectrum_send,
+            spectrum_pool,
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.history[self.history_idx] = sample;
+        self.history_idx = (self.history_idx + 1) % INPUT_FFT_LEN;
+        self.since_hop += 1;
+        if self.since_hop >= INPUT_HOP_LEN {
+            self.since_hop = 0;
+            self.analyze();
+        }
+    }
+
+    fn analyze(&mut self) {
+        for (idx, value) in self.fft_in.iter_mut().enumerate() {
+            *value = self.history[(self.history_idx + idx) % INPUT_FFT_LEN] * self.window[idx];
+        }
+        self.fft.process_with_scratch(&mut self.fft_in, &mut self.fft_out, &mut self.fft_scratch).unwrap();
+
+        // If the render loop is holding on to every spectrum, this one is dropped
+        let Some(mut spectrum) = self.spectrum_pool.take() else { return };
+        spectrum.clear();
+        spectrum.extend(self.fft_out[1..=INPUT_BINS].iter().map(|bin| bin.norm()));
+        if let Err(e) = self.spectrum_send.try_send(spectrum) {
+            self.spectrum_pool.give(e.into_inner());
+        }
+    }
+}
+
+// The render loop's end of the input: gathers spectra as they arrive, and turns them into odds for each new row.
+// Also keeps whatever is feeding it alive.
+pub struct InputBias {
+    config: InputConfig,
+    sample_rate: f32,
+    spectrum_recv: crossbeam_channel::Receiver<Vec<f32>>,
+    spectrum_pool: SpectrumPool, // Where spectra go once read, back to the analyzer
+    levels: Vec<f32>,           // Loudest each bin has been since the last row
+    heard: bool,                // Whether anything has arrived since the last row
+    last_odds: Vec<f32>,        // Reused if nothing arrives between rows
+    _stream: Option<cpal::Stream>,
+    _thread: Option<WorkerThread>,
+}
+
+impl InputBias {
+    // Chance each of the given number of tiles, left to right, gets flipped in the next row. Loud bands flip more.
+    pub fn flip_odds(&mut self, columns: usize) -> Vec<f32> {
+        while let Ok(spectrum) = self.spectrum_recv.try_recv() {
+            for (level, &new) in self.levels.iter_mut().zip(spectrum.iter()) {
+                *level = level.max(new);
+            }
+            self.spectrum_pool.give(spectrum);
+            self.heard = true;
+        }
+        if !self.heard && self.last_odds.len() == columns {
+            return self.last_odds.clone();
+        }
+
+        let bin_hz = self.sample_rate / INPUT_FFT_LEN as f32;
+        let high_hz = INPUT_HIGH_HZ.min(self.sample_rate*0.45);
+        let column_hz = |column: usize| INPUT_LOW_HZ * (high_hz/INPUT_LOW_HZ).powf(column as f32 / columns as f32);
+        // Bin idx is at frequency (idx+1)*bin_hz
+        let bin_at = |hz: f32| ((hz / bin_hz).round() as usize).clamp(1, INPUT_BINS) - 1;
+
+        let odds:Vec<f32> = (0..columns).map(|column| {
+            // Low columns can be narrower than a bin; those just look at the nearest one
+            let (start, end) = (bin_at(column_hz(column)), bin_at(column_hz(column+1)));
+            let peak = self.levels[start..=end.max(start)].iter().copied().fold(0., f32::max);
+            let db = 20. * peak.max(f32::MIN_POSITIVE).log10();
+            let level = (1. - db/self.config.floor_db).clamp(0., 1.);
+            0.5 + self.config.amount.clamp(0., 1.)*(level - 0.5)
+        }).collect();
+
+        self.levels.fill(0.);
+        self.heard = false;
+        self.last_odds = odds.clone();
+        odds
+    }
+}
+
+fn input_run<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut analyzer: InputAnalyzer) -> Result<cpal::Stream, AudioError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let err_fn = |err| eprintln!("an error occurred on input stream: {}", err);
+
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            // Mix down to mono
+            realtime(|| {
+                for frame in data.chunks(channels) {
+                    let sum:f32 = frame.iter().map(|&sample| f32::from_sample(sample)).sum();
+                    analyzer.push(sum / channels as f32);
+                }
+            })
+        },
+        err_fn,
+        None,
+    )?;
+    stream.play()?;
+
+    Ok(stream)
+}
+
+// Start listening. host is the same --host the output uses.
+pub fn input_spawn(config: &InputConfig, host: &Option<String>) -> Result<Option<InputBias>, AudioError> {
+    let Some(source) = &config.source else { return Ok(None) };
+    let (spectrum_send, spectrum_recv) = crossbeam_channel::bounded::<Vec<f32>>(INPUT_SPECTRA_MAX_INFLIGHT);
+    let spectrum_pool = SpectrumPool::new();
+    let mut analyzer = InputAnalyzer::new(spectrum_send, spectrum_pool.clone());
+
+    let (sample_rate, stream, thread) = match source {
+        InputSource::File(path) => {
+            let (samples, sample_rate) = wav_read(path)?;
+            if samples.is_empty() {
+                return Err(AudioError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, "WAV file is empty")));
+            }
+            println!("Listening to {}", path.display());
+
+            // Feed the analyzer at the pace the file would play at, so the picture keeps time with it
+            let thread = WorkerThread::spawn("input file", move |quit| {
+                let start = Instant::now();
+                let mut read:u64 = 0;
+                let mut samples = samples.iter().copied().cycle();
+                while !quit.load(Ordering::Relaxed) {
+                    for sample in samples.by_ref().take(INPUT_FILE_BLOCK_LEN) {
+                        analyzer.push(sample);
+                    }
+                    read += INPUT_FILE_BLOCK_LEN as u64;
+
+                    let due = start + Duration::from_secs_f64(read as f64 / sample_rate as f64);
+                    let now = Instant::now();
+                    if due > now {
+                        std::thread::sleep(due - now);
+                    }
+                }
+            });
+            (sample_rate, None, Some(thread))
+        },
+        InputSource::Device(request) => {
+            let host = choose_host(host)?;
+            let device = match request {
+                None => host.default_input_device().ok_or(AudioError::NoInputDevice)?,
+                Some(request) => {
+                    let mut devices:Vec<cpal::Device> = host.input_devices()?.collect();
+                    let names:Vec<String> = devices.iter().map(device_name).collect();
+                    let idx = choose_by_name(request, &names).ok_or_else(|| AudioError::NoMatchingInputDevice(request.clone()))?;
+                    devices.swap_remove(idx)
+                }
+            };
+            let supported = device.default_input_config()?;
+            let sample_format = supported.sample_format();
+            let stream_config:cpal::StreamConfig = supported.into();
+            println!("Listening to {}", device_name(&device));
+
+            let stream = match sample_format {
+                cpal::SampleFormat::I8 => input_run::<i8>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::I16 => input_run::<i16>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::I32 => input_run::<i32>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::I64 => input_run::<i64>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::U8 => input_run::<u8>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::U16 => input_run::<u16>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::U32 => input_run::<u32>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::U64 => input_run::<u64>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::F32 => input_run::<f32>(&device, &stream_config, analyzer),
+                cpal::SampleFormat::F64 => input_run::<f64>(&device, &stream_config, analyzer),
+                sample_format => Err(AudioError::UnsupportedFormat(sample_format)),
+            }?;
+            (stream_config.sample_rate.0, Some(stream), None)
+        },
+    };
+
+    Ok(Some(InputBias {
+        config: config.clone(),
+        sample_rate: sample_rate as f32,
+        spectrum_recv,
+        spectrum_pool,
+        levels: vec![0.; INPUT_BINS],
+        heard: false,
+        last_odds: Default::default(),
+        _stream: stream,
+        _thread: thread,
+    }))
+}
@@ -3,22 +3,40 @@
 mod audio;
 mod boilerplate;
 mod constants;
+mod control;
 mod diagonal;
+mod dither;
+mod effects;
+mod granular;
+mod input;
+mod midi;
+mod osc;
+mod readback;
+mod realtime;
+mod recorder;
+mod resample;
+mod scan;
+mod settings;
+mod synth;
+mod wav;
+mod wavetable;
+mod worker;
 
 use std::array;
 use std::borrow::Cow;
 use std::mem;
 use std::num::NonZeroU64;
 use std::ops::DerefMut;
-use std::sync::Arc;
-use atomic_refcell::AtomicRefCell;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 use crossbeam_channel::bounded;
 use divrem::DivCeil;
-use rand::Rng;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use web_time::{Duration, Instant};
 use winit::{
-    event::{Event, WindowEvent},
+    event::{ElementState, Event, KeyEvent, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
+    keyboard::Key,
     window::Window, dpi::PhysicalSize,
 };
 use wgpu::util::DeviceExt;
@@ -26,11 +44,36 @@ use wgpu::util::DeviceExt;
 #[cfg(target_arch="wasm32")]
 use winit::platform::web::WindowExtWebSys;
 
+use crate::audio::{AudioBackend, AudioControls, AudioError};
 use crate::boilerplate::*;
 use crate::constants::*;
+use crate::control::*;
 use crate::diagonal::*;
+use crate::input::InputBias;
+use crate::midi::MidiRecorder;
+use crate::osc::{OscArg, OscSender};
+use crate::readback::*;
+use crate::scan::ScanHeadConfig;
+use crate::settings::*;
+use crate::synth::{AudioFrame, AudioStats, FramePool};
+
+// Everything the render loop shares with the rest of the program
+struct RunContext {
+    listeners: ReadbackListeners,
+    readback_row_send: Option<crossbeam_channel::Sender<Box<ReadbackRow>>>, // To the readback thread; None to deliver rows in place
+    audio_controls: Arc<AudioControls>,
+    audio_stats: Arc<AudioStats>, // Counts rows the readback thread couldn't take
+    readback_len: usize, // Pixels in the readback row: one per frequency bin the synthesizer has
+    precision: Precision, // Format of the blur and readback textures
+    heads: Vec<ScanHeadConfig>, // Where rows are read back from, one per voice
+    markers: bool, // Whether to draw the heads on screen
+    control_recv: crossbeam_channel::Receiver<Control>,
+    input: Option<InputBias>,
+}
+
+async fn run(event_loop: EventLoop<()>, window: Window, mut visuals: VisualConfig, context: RunContext) {
+    let RunContext { listeners, readback_row_send, audio_controls, audio_stats, readback_len, precision, heads, markers, control_recv, mut input } = context;
 
-async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossbeam_channel::Sender<Box<AudioChunk>>) {
     // ----------------------- Basic setup ----------------------
 
     let size = window.inner_size();
@@ -101,7 +144,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
     // ------ Data/operations for init/resize ------
 
     // Parts for diagonal (will be needed on resize)
-    let (diagonal_vertex_buffer, diagonal_index_buffer, diagonal_index_len, diagonal_vertex_layout) = make_diagonal_buffers(&device, DEFAULT_STROKE);
+    let (mut diagonal_vertex_buffer, mut diagonal_index_buffer, diagonal_index_len, diagonal_vertex_layout) = make_diagonal_buffers(&device, visuals.stroke);
 
     // Throw away diagonal pipeline layout, we will not be attaching bind groups
     let (_, diagonal_render_pipeline) = make_pipeline(&device, &shader, &[], "vs_plain", &[diagonal_vertex_layout], "fs_plain", &[Some(wgpu::TextureFormat::R8Unorm.into())], "diagonal");
@@ -125,7 +168,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
             ty: wgpu::BindingType::Buffer {
                 ty: wgpu::BufferBindingType::Uniform,
                 has_dynamic_offset: false,
-                min_binding_size: wgpu::BufferSize::new((mem::size_of::<f32>()*2) as u64),
+                min_binding_size: wgpu::BufferSize::new((mem::size_of::<u32>()*6) as u64), // Blur step, then threshold (see VisualConfig::threshold_uniform)
             },
             count: None,
         }], "Target");
@@ -156,7 +199,42 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         ]
     });
 
-    let readback_bind_group_layout = make_texture_bind_group_layout(&device, &[], "Readback");
+    // Source texture, then the row read out of it, then the scan head to read it along
+    let readback_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Readback bind group layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage {read_only:false},
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new((mem::size_of::<u32>()) as u64),
+                },
+                count: None
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new((mem::size_of::<[u32; 8]>()) as u64),
+                },
+                count: None
+            },
+        ]
+    });
 
     let default_sampler = make_sampler(&device);
 
@@ -167,6 +245,15 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
+    // One per scan head, rewritten every frame as the heads move
+    let readback_uniform_buffers: Vec<wgpu::Buffer> = heads.iter().enumerate().map(|(idx, head)|
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("Readback-{} Uniform Buffer", idx)),
+            contents: bytemuck::cast_slice(&head.uniform(0., readback_len)),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        })
+    ).collect();
+
     const ZERO_U32: [u32; 1] = [0];
     let rowshift_uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Row shift shift Uniform Buffer"),
@@ -174,25 +261,73 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
-    const TARGET_PASSES:usize = 8;
-    let target_uniform_buffers: [wgpu::Buffer; TARGET_PASSES] = array::from_fn(|idx|
-        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("Target-{} Uniform Buffer", idx+1)),
-            contents: bytemuck::cast_slice(&ZERO_ZERO_F32),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        })
-    );
+    // Only the final pass uses the threshold, but which pass is final can change, so every pass gets it.
+    // It sits after the blur step, which generate_blur_chain fills in.
+    fn write_threshold(queue: &wgpu::Queue, target_uniform_buffers: &[wgpu::Buffer], visuals: &VisualConfig) {
+        for buffer in target_uniform_buffers {
+            queue.write_buffer(buffer, (mem::size_of::<f32>()*2) as u64, bytemuck::cast_slice(&visuals.threshold_uniform()));
+        }
+    }
+
+    // The chain of blur passes: a uniform buffer and bind group for each. They take turns reading the two target
+    // textures, so this has to be redone whenever those are remade, as well as when the chain itself changes.
+    // Steps are scaled from BLUR_REFERENCE_TILE to the tiles actually drawn, tile_side pixels across.
+    fn generate_blur_chain(size:PhysicalSize<u32>, tile_side:u32, device: &wgpu::Device, target_bind_group_layout:&wgpu::BindGroupLayout, default_sampler:&wgpu::Sampler, target_views:&[wgpu::TextureView;2], visuals:&VisualConfig) -> (Vec<wgpu::Buffer>, Vec<wgpu::BindGroup>) {
+        let scale = tile_side as f32 / BLUR_REFERENCE_TILE;
+        let target_uniform_buffers: Vec<wgpu::Buffer> = (0..visuals.blur_passes).map(|stage| {
+            let step = visuals.blur_step(stage) * scale;
+            let step: [f32; 2] =
+                if 0==stage%2 { [step/size.width as f32, 0.] }   // Even passes X-blur
+                else          { [0., step/size.height as f32] }; // Odd passes Y-blur
+            let [low, high, invert, hard] = visuals.threshold_uniform();
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("Target-{} Uniform Buffer", stage+1)),
+                contents: bytemuck::cast_slice(&[step[0].to_bits(), step[1].to_bits(), low, high, invert, hard]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            })
+        }).collect();
+
+        // They can reuse texture targets, but each one needs its own parameters.
+        let target_bind_groups = target_uniform_buffers.iter().enumerate().map(|(stage, buffer)|
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&target_views[stage%2]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(default_sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: buffer.as_entire_binding(),
+                    }
+                ],
+                layout: target_bind_group_layout,
+                label: Some(&format!("target-{} bind group", stage)),
+            })
+        ).collect();
+
+        (target_uniform_buffers, target_bind_groups)
+    }
+
+    // Tile flips; seeded, if asked, so a run can be repeated
+    let mut grid_rng = match visuals.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
 
     // Triangle order for a quad in grid or target passes
     const GRID_INDEX_BASE : [u16;6] = [0, 2, 1,
                                        1, 2, 3];
 
-    // FIXME: Add some way to set this to 0 at runtime (for a "mute").
+    // Readback stops entirely while muted (see audio_controls)
     const AUDIO_READBACK_BUFFER_MAX_INFLIGHT:usize = 2;
 
     // Create a quad UV buffer with random reflection. Assumes grid_uv is a multiple of 8.
-    fn random_uv_push(grid_uv: &mut [f32]) {
-        let mut rng = rand::thread_rng();
+    // odds gives the chance each tile along a row is flipped; tiles past its end get even odds
+    fn random_uv_push(grid_uv: &mut [f32], rng: &mut StdRng, odds: &[f32]) {
 
         const GRID_UV_BASE: [f32;8] = [
             0., 0.,
@@ -204,7 +339,10 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         // Generate 8 values at a time, by copying in GRID_UV_BASE and perturbing the X UV.
         let mut base = 0;
         while base < grid_uv.len() {
-            let flip = rng.gen::<bool>();
+            let flip = match odds.get(base/8) {
+                Some(&chance) => rng.gen::<f32>() < chance,
+                None => rng.gen::<bool>()
+            };
             for idx in 0..8 {
                 let mut value = GRID_UV_BASE[idx];
                 if 0==idx%2 && flip { value = 1. - value }
@@ -214,7 +352,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         }
     }
 
-    fn generate_resize(size:PhysicalSize<u32>, device: &wgpu::Device, queue: &wgpu::Queue, surface: &wgpu::Surface, swapchain_format: wgpu::TextureFormat, swapchain_capabilities: &wgpu::SurfaceCapabilities, diagonal_vertex_buffer: &wgpu::Buffer, diagonal_index_buffer: &wgpu::Buffer, diagonal_index_len: usize, diagonal_render_pipeline: &wgpu::RenderPipeline, grid_bind_group_layout: &wgpu::BindGroupLayout, default_sampler:&wgpu::Sampler, grid_uniform_buffer:&wgpu::Buffer, rowshift_bind_group_layout:&wgpu::BindGroupLayout, rowshift_uniform_buffer:&wgpu::Buffer, target_bind_group_layout:&wgpu::BindGroupLayout, target_uniform_buffers:&[wgpu::Buffer;TARGET_PASSES], readback_bind_group_layout:&wgpu::BindGroupLayout) -> (u32, f32, u64, u64, wgpu::Texture, wgpu::Buffer, wgpu::Buffer, wgpu::Buffer, u32, wgpu::BindGroup, wgpu::BindGroup, wgpu::util::StagingBelt, wgpu::BufferAddress, wgpu::BufferSize, [wgpu::TextureView;2], [wgpu::BindGroup;TARGET_PASSES], wgpu::Texture, wgpu::TextureView, wgpu::BindGroup, Vec<Arc<wgpu::Buffer>>, crossbeam_channel::Sender<Arc<wgpu::Buffer>>, crossbeam_channel::Receiver<Arc<wgpu::Buffer>>) {
+    fn generate_resize(size:PhysicalSize<u32>, device: &wgpu::Device, queue: &wgpu::Queue, surface: &wgpu::Surface, swapchain_format: wgpu::TextureFormat, swapchain_capabilities: &wgpu::SurfaceCapabilities, diagonal_vertex_buffer: &wgpu::Buffer, diagonal_index_buffer: &wgpu::Buffer, diagonal_index_len: usize, diagonal_render_pipeline: &wgpu::RenderPipeline, grid_bind_group_layout: &wgpu::BindGroupLayout, default_sampler:&wgpu::Sampler, grid_uniform_buffer:&wgpu::Buffer, rowshift_bind_group_layout:&wgpu::BindGroupLayout, rowshift_uniform_buffer:&wgpu::Buffer, readback_bind_group_layout:&wgpu::BindGroupLayout, readback_uniform_buffers:&[wgpu::Buffer], readback_len:usize, precision:Precision, tiles_across:u32, grid_rng:&mut StdRng) -> (u32, f32, u64, u64, wgpu::Texture, wgpu::Buffer, wgpu::Buffer, wgpu::Buffer, u32, wgpu::BindGroup, wgpu::BindGroup, wgpu::util::StagingBelt, wgpu::BufferAddress, wgpu::BufferSize, [wgpu::TextureView;2], Vec<wgpu::Buffer>, wgpu::TextureView, Vec<[wgpu::BindGroup;3]>, Vec<Arc<wgpu::Buffer>>, crossbeam_channel::Sender<Arc<wgpu::Buffer>>, crossbeam_channel::Receiver<Arc<wgpu::Buffer>>) {
         // Set size
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -231,8 +369,8 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         // ------ Diagonal ------
 
         // Decide how big the diagonal texture should be
-        // TODO: What should TILES_ACROSS be? Should TILES_ACROSS depend on window DPI?
-        let diagonal_texture_side = std::cmp::min(DivCeil::div_ceil(size.height, TILES_ACROSS), DivCeil::div_ceil(size.width, TILES_ACROSS));
+        // TODO: What should tiles_across default to? Should it depend on window DPI?
+        let diagonal_texture_side = std::cmp::min(DivCeil::div_ceil(size.height, tiles_across), DivCeil::div_ceil(size.width, tiles_across));
 
         let (diagonal_texture, diagonal_view) = make_texture_gray(&device, diagonal_texture_side, diagonal_texture_side, true, false, "diagonal-texture");
 
@@ -280,7 +418,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         ];
 
         let mut grid_vertex:Vec<f32>  = Default::default();
-        let mut grid_index:Vec<u16>   = Default::default();
+        let mut grid_index:Vec<u32>   = Default::default(); // Many tiles need more than 16-bit indices
 
         // Fill out grid vertex and index buffers
         let (across_x, across_y) = ((2./side_x).ceil() as i64,
@@ -288,7 +426,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         let (offset_x, offset_y) = ((across_x as f32-1.)*side_x/2.,
                                     1.-side_y/2.);
         {
-            let mut index_offset:u16 = 0;
+            let mut index_offset:u32 = 0;
             for y in 0..across_y {
                 for x in 0..across_x {
                     for idx in 0..8 {
@@ -303,7 +441,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                         }
                     }
                     for idx in 0..6 {
-                        let value = GRID_INDEX_BASE[idx] + index_offset*4;
+                        let value = GRID_INDEX_BASE[idx] as u32 + index_offset*4;
                         grid_index.push(value);
                     }
                     index_offset += 1;
@@ -336,7 +474,7 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
 
         { // ...and then write bytes to write-mapped uv buffer
             let mut mapped_bytes = grid_uv_buffer.slice(..).get_mapped_range_mut();
-            random_uv_push(bytemuck::cast_slice_mut::<u8, f32>(&mut mapped_bytes));
+            random_uv_push(bytemuck::cast_slice_mut::<u8, f32>(&mut mapped_bytes), grid_rng, &[]);
         }
         grid_uv_buffer.unmap();
 
@@ -392,36 +530,30 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
 
         // The blur needs to happen multiple times to look soft. Make two back-buffer textures; we'll render out of one into the other, then swap.
         let target_views: [wgpu::TextureView; 2] = array::from_fn(|view_idx| {
-            let (_, target_view) = make_texture_gray(&device, size.width, size.height, true, false, &format!("target texture {}", view_idx));
+            let (_, target_view) = make_texture(&device, size.width, size.height, precision.format(), true, false, &format!("target texture {}", view_idx));
             target_view
         });
 
-        const BLUR_SCALE_BASE:u32 = 1;
+        // The readback shader writes each head's row into one of these, and they're copied one after another into a mappable buffer from the pool below.
+        // Texture-to-buffer copies need 256-byte-aligned rows; buffer-to-buffer copies only need whole words, so any readback_len works.
+        let readback_row_bytes = precision.row_bytes(readback_len) as u64;
+        let readback_storage_buffers: Vec<wgpu::Buffer> = (0..readback_uniform_buffers.len()).map(|idx| device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&format!("Readback storage buffer {}", idx)),
+            size: readback_row_bytes,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC
+        })).collect();
 
-        // Bind groups for our chain of blur passes; they can reuse texture targets, but each one needs its own parameters.
-        let target_bind_groups: [wgpu::BindGroup; TARGET_PASSES] = array::from_fn(|stage| {            
-            texture_bind_group(&target_views[stage%2], &target_uniform_buffers[stage], &target_bind_group_layout, &format!("target-{} bind group", stage))
-        });
-
-        // Fill out blur-pass parameters
-        for stage in 0..TARGET_PASSES {
-            let blur_scale = (BLUR_SCALE_BASE << (stage/2)) as f32;
-            let target_buffer_contents: [f32; 2] =
-                if 0==stage%2 { [blur_scale/size.width as f32, 0.] }   // Even passes X-blur
-                else          { [0., blur_scale/size.height as f32] }; // Odd passes Y-blur
-            queue.write_buffer(&target_uniform_buffers[stage], 0, bytemuck::cast_slice(&target_buffer_contents));
-        }
-
-        // Read-back texture
-        // FIXME: Should this be a 1D texture instead of a 1-height 2D texture? Does it even matter?
-        let (readback_texture, readback_view) = make_texture_gray(&device, AUDIO_READBACK_BUFFER_LEN as u32, 1, true, true, "readback texture");
+        // The thresholded image goes straight to the screen, which can't be read from, so when the audio wants to hear
+        // it we draw the last pass again in here.
+        let (_, tap_view) = make_texture(&device, size.width, size.height, precision.format(), true, false, "tap texture");
 
         // Pool of read-back buffers (buffers will be used by callback, so has to be refcounted, and callback is 'Send so the Rust typesystem forces an unnecessary atomicity requirement)
         let mut readback_buffers:Vec<Arc<wgpu::Buffer>> = Default::default();
         for idx in 0..AUDIO_READBACK_BUFFER_MAX_INFLIGHT {
             readback_buffers.push(Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
                 label: Some(&format!("Readback buffer {}", idx)),
-                size: AUDIO_READBACK_BUFFER_LEN as u64, // Returned values are u8
+                size: readback_row_bytes * readback_storage_buffers.len() as u64,
                 mapped_at_creation: false,
                 usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ // Mutable, can be targeted by copies or by shaders
             })));
@@ -429,32 +561,38 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         // Using sync_channel because it's theoretically more efficient and we can't overflow it.
         let (readback_buffer_send, readback_buffer_recv) = crossbeam_channel::bounded::<Arc<wgpu::Buffer>>(AUDIO_READBACK_BUFFER_MAX_INFLIGHT);
 
-        // Bind group for write into read-back texture
-        // Can't use texture_bind_group because no parameters
-        // Attempt to read from "final" view texture
-        let readback_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&target_views[(TARGET_PASSES+1)%2]),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&default_sampler),
-                },
-            ],
-            layout: &readback_bind_group_layout,
-            label: Some("Readback bind group"),
-        });
+        // Bind groups for each head reading back from each back-buffer texture, which after a given number of passes is readback_bind_groups[head][passes%2], then one for the tap texture
+        let readback_bind_groups: Vec<[wgpu::BindGroup; 3]> = readback_storage_buffers.iter().zip(readback_uniform_buffers).map(|(storage_buffer, uniform_buffer)|
+            array::from_fn(|view_idx| device.create_bind_group(&wgpu::BindGroupDescriptor {
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(if view_idx < 2 { &target_views[view_idx] } else { &tap_view }),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: storage_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
+                ],
+                layout: &readback_bind_group_layout,
+                label: Some("Readback bind group"),
+            }))
+        ).collect();
 
-        (diagonal_texture_side, side_y, across_x.try_into().unwrap(), across_y.try_into().unwrap(), diagonal_texture, grid_vertex_buffer, grid_uv_buffer, grid_index_buffer, grid_index.len() as u32, grid_bind_group, rowshift_bind_group, grid_uv_staging_belt, grid_uv_staging_offset, NonZeroU64::new(grid_uv_staging_size).unwrap(), target_views, target_bind_groups, readback_texture, readback_view, readback_bind_group, readback_buffers, readback_buffer_send, readback_buffer_recv)
+        (diagonal_texture_side, side_y, across_x.try_into().unwrap(), across_y.try_into().unwrap(), diagonal_texture, grid_vertex_buffer, grid_uv_buffer, grid_index_buffer, grid_index.len() as u32, grid_bind_group, rowshift_bind_group, grid_uv_staging_belt, grid_uv_staging_offset, NonZeroU64::new(grid_uv_staging_size).unwrap(), target_views, readback_storage_buffers, tap_view, readback_bind_groups, readback_buffers, readback_buffer_send, readback_buffer_recv)
     }
 
-    let (mut diagonal_texture_side, mut diagonal_texture_side_ndc, mut diagonal_texture_count_x, mut diagonal_texture_count_y, mut diagonal_texture, mut grid_vertex_buffer, mut grid_uv_buffer, mut grid_index_buffer, mut grid_index_len, mut grid_bind_group, mut rowshift_bind_group, mut grid_uv_staging_belt, mut grid_uv_staging_offset, mut grid_uv_staging_size, mut target_views, mut target_bind_groups, mut readback_texture, mut readback_view, mut readback_bind_group, mut readback_buffers, mut readback_buffer_send, mut readback_buffer_recv) = generate_resize(size, &device, &queue, &surface, swapchain_format, &swapchain_capabilities, &diagonal_vertex_buffer, &diagonal_index_buffer, diagonal_index_len, &diagonal_render_pipeline, &grid_bind_group_layout, &default_sampler, &grid_uniform_buffer, &rowshift_bind_group_layout, &rowshift_uniform_buffer, &target_bind_group_layout, &target_uniform_buffers, &readback_bind_group_layout);
+    let (mut diagonal_texture_side, mut diagonal_texture_side_ndc, mut diagonal_texture_count_x, mut diagonal_texture_count_y, mut diagonal_texture, mut grid_vertex_buffer, mut grid_uv_buffer, mut grid_index_buffer, mut grid_index_len, mut grid_bind_group, mut rowshift_bind_group, mut grid_uv_staging_belt, mut grid_uv_staging_offset, mut grid_uv_staging_size, mut target_views, mut readback_storage_buffers, mut tap_view, mut readback_bind_groups, mut readback_buffers, mut readback_buffer_send, mut readback_buffer_recv) = generate_resize(size, &device, &queue, &surface, swapchain_format, &swapchain_capabilities, &diagonal_vertex_buffer, &diagonal_index_buffer, diagonal_index_len, &diagonal_render_pipeline, &grid_bind_group_layout, &default_sampler, &grid_uniform_buffer, &rowshift_bind_group_layout, &rowshift_uniform_buffer, &readback_bind_group_layout, &readback_uniform_buffers, readback_len, precision, visuals.tiles, &mut grid_rng);
+    let (mut target_uniform_buffers, mut target_bind_groups) = generate_blur_chain(size, diagonal_texture_side, &device, &target_bind_group_layout, &default_sampler, &target_views, &visuals);
+    let mut blur_size = size; // Window size the blur chain was last made for
 
     // ------ Data/operations for frame draw ------
 
-    let (render_pipeline_layout, render_pipeline) = make_pipeline(&device, &shader, &[&grid_bind_group_layout], "vs_textured_offset", &[VEC2_LAYOUT, VEC2_LAYOUT_LOCATION_1], "fs_textured", &[Some(wgpu::TextureFormat::R8Unorm.into())], "grid");
+    let (render_pipeline_layout, render_pipeline) = make_pipeline(&device, &shader, &[&grid_bind_group_layout], "vs_textured_offset", &[VEC2_LAYOUT, VEC2_LAYOUT_LOCATION_1], "fs_textured", &[Some(precision.format().into())], "grid");
 
     let rowshift_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
         label: Some("Row shift pipeline"),
@@ -467,8 +605,10 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         entry_point: "internal_copy",
     });
 
-    let (target_pipeline_layout, target_pipeline) = make_pipeline(&device, &shader, &[&target_bind_group_layout], "vs_textured", &[VEC2X2_LAYOUT], "fs_postprocess_blur", &[Some(wgpu::TextureFormat::R8Unorm.into())], "target-blur");
+    let (target_pipeline_layout, target_pipeline) = make_pipeline(&device, &shader, &[&target_bind_group_layout], "vs_textured", &[VEC2X2_LAYOUT], "fs_postprocess_blur", &[Some(precision.format().into())], "target-blur");
     let (target_final_pipeline_layout, target_final_pipeline) = make_pipeline(&device, &shader, &[&target_bind_group_layout], "vs_textured", &[VEC2X2_LAYOUT], "fs_postprocess_blur_threshold", &[Some(swapchain_format.into())], "target-blur-threshold");
+    // Same, but into the tap texture (see AudioTap::Threshold)
+    let (_, target_tap_pipeline) = make_pipeline(&device, &shader, &[&target_bind_group_layout], "vs_textured", &[VEC2X2_LAYOUT], "fs_postprocess_blur_threshold", &[Some(precision.format().into())], "target-blur-threshold-tap");
 
     let (target_vertex_buffer, target_index_buffer, target_index_len) = {
         // Combined vertex and UV for a full-screen quad
@@ -497,26 +637,44 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         (target_vertex_buffer, target_index_buffer, 6)
     };
 
-    let (readback_pipeline_layout, readback_pipeline) = make_pipeline(&device, &shader, &[&readback_bind_group_layout], "vs_textured", &[VEC2X2_LAYOUT], "fs_textured_readback", &[Some(wgpu::TextureFormat::R8Unorm.into())], "readback");
-
-    let mut fft_planner = realfft::RealFftPlanner::<f64>::new();
-    let fft = Arc::new(fft_planner.plan_fft_inverse(AUDIO_READBACK_BUFFER_LEN*2));
-    // We'd like to reuse these buffers, but the Wgpu callback thinks it's running on another thread (per the documentation, it's not)
-    // Since we don't actually need thread safety, we don't want to pay for RwLock. So we use the atomic_refcell crate:
-    // FIXME: probably using thread-local variables here would be more straightforward
-    let fft_in = Arc::new(AtomicRefCell::new(fft.make_input_vec()));
-    let fft_out = Arc::new(AtomicRefCell::new(fft.make_output_vec()));
+    let readback_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Readback pipeline"),
+        layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Readback pipeline layout"),
+            bind_group_layouts:&[&readback_bind_group_layout],
+            push_constant_ranges:&[]
+        })),
+        module: &shader,
+        entry_point: precision.readback_entry(),
+    });
+    // The shader writes one word per invocation
+    const READBACK_WORKGROUP_LEN:usize = 64;
+    let readback_workgroups = (precision.row_bytes(readback_len)/4).div_ceil(READBACK_WORKGROUP_LEN) as u32;
+
+    // Scan heads drawn over the finished picture, with --markers; the vertices move with the heads every frame
+    let (_, marker_pipeline) = make_pipeline(&device, &shader, &[], "vs_plain", &[VEC2_LAYOUT], "fs_marker", &[Some(swapchain_format.into())], "marker");
+    let marker_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Marker vertex buffer"),
+        size: (heads.len()*6*mem::size_of::<[f32; 2]>()) as u64,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+    });
 
     let mut grid_last_reset = Instant::now();
     let mut grid_last_reset_overflow = 0.;
+    let mut grid_rows:u64 = 0; // Row shifts so far
+
+    // Set by a resize, or a control that needs everything rebuilt; dealt with before the next frame
+    let mut pending_resize: Option<PhysicalSize<u32>> = None;
 
-    let fft_window:[f64;AUDIO_CHUNK_LEN] = apodize::hanning_iter(AUDIO_CHUNK_LEN).collect::<Vec<f64>>().try_into().unwrap();
+    // Audio frames are timestamped relative to this, so the audio thread can pace itself by them
+    let audio_epoch = Instant::now();
 
     event_loop.run(move |event, target| {
         // Have the closure take ownership of the resources.
         // `event_loop.run` never returns, therefore we must do this to ensure
         // the resources do not leak.
-        let _ = (&instance, &adapter, &shader, &render_pipeline_layout, &fft, &fft_in, &fft_out);
+        let _ = (&instance, &adapter, &shader, &render_pipeline_layout);
 
         target.set_control_flow(ControlFlow::Poll);
         if cfg!(feature = "metal-auto-capture") {
@@ -526,14 +684,79 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::Resized(size) => {
-                    // Reconfigure the surface with the new size
-                    (diagonal_texture_side, diagonal_texture_side_ndc, diagonal_texture_count_x, diagonal_texture_count_y, diagonal_texture, grid_vertex_buffer, grid_uv_buffer, grid_index_buffer, grid_index_len, grid_bind_group, rowshift_bind_group, grid_uv_staging_belt, grid_uv_staging_offset, grid_uv_staging_size, target_views, target_bind_groups, readback_texture, readback_view, readback_bind_group, readback_buffers, readback_buffer_send, readback_buffer_recv) = generate_resize(size, &device, &queue, &surface, swapchain_format, &swapchain_capabilities, &diagonal_vertex_buffer, &diagonal_index_buffer, diagonal_index_len, &diagonal_render_pipeline, &grid_bind_group_layout, &default_sampler, &grid_uniform_buffer, &rowshift_bind_group_layout, &rowshift_uniform_buffer, &target_bind_group_layout, &target_uniform_buffers, &readback_bind_group_layout);
+                    // Reconfigure the surface with the new size, before drawing the next frame
+                    pending_resize = Some(size);
                     // On macos the window needs to be redrawn manually after resizing
                     window.request_redraw();
                 }
                 WindowEvent::RedrawRequested => {
                     device.poll(wgpu::MaintainBase::Poll); // Flush out unmaps from last frames before doing any work. // FIXME: IS THIS ACTUALLY HELPFUL?
 
+                    // Apply any changes sent from outside (see osc.rs), here between frames
+                    let mut blur_pending = false;
+                    while let Ok(control) = control_recv.try_recv() {
+                        match control {
+                            Control::Speed(speed) => {
+                                // Keep the scroll where it is, and carry on from there at the new speed
+                                let now = Instant::now();
+                                grid_last_reset_overflow += now.duration_since(grid_last_reset).as_secs_f32()*visuals.speed;
+                                grid_last_reset = now;
+                                visuals.speed = speed;
+                                if let Some(midi) = &listeners.midi {
+                                    midi.lock().unwrap().set_speed(grid_rows, speed);
+                                }
+                            },
+                            // These two start the grid over, just like a resize
+                            Control::Stroke(stroke) => {
+                                visuals.stroke = stroke;
+                                (diagonal_vertex_buffer, diagonal_index_buffer, _, _) = make_diagonal_buffers(&device, stroke);
+                                pending_resize = Some(window.inner_size());
+                            },
+                            Control::Tiles(tiles) => {
+                                visuals.tiles = tiles;
+                                pending_resize = Some(window.inner_size());
+                            },
+                            Control::Seed(seed) => grid_rng = StdRng::seed_from_u64(seed),
+                            Control::Threshold(low, high) => {
+                                visuals.threshold = (low, high);
+                                write_threshold(&queue, &target_uniform_buffers, &visuals);
+                            },
+                            Control::ThresholdMode(mode) => {
+                                visuals.threshold_mode = mode;
+                                write_threshold(&queue, &target_uniform_buffers, &visuals);
+                            },
+                            Control::ThresholdInvert(invert) => {
+                                visuals.threshold_invert = invert;
+                                write_threshold(&queue, &target_uniform_buffers, &visuals);
+                            },
+                            // These three remake the blur chain, below
+                            Control::BlurPasses(passes) => {
+                                visuals.blur_passes = passes.clamp(1, BLUR_PASSES_MAX);
+                                blur_pending = true;
+                            },
+                            Control::BlurRadius(radius) => {
+                                visuals.blur_radius = radius;
+                                blur_pending = true;
+                            },
+                            Control::BlurGrowth(growth) => {
+                                visuals.blur_growth = growth;
+                                blur_pending = true;
+                            },
+                            Control::Tap(tap) => visuals.tap = tap,
+                            Control::Volume(gain_db) => audio_controls.set_gain_db(gain_db),
+                            Control::Mute(muted) => audio_controls.set_muted(muted),
+                            Control::Record(recording) => audio_controls.set_recording(recording),
+                        }
+                    }
+                    if let Some(size) = pending_resize.take() {
+                        (diagonal_texture_side, diagonal_texture_side_ndc, diagonal_texture_count_x, diagonal_texture_count_y, diagonal_texture, grid_vertex_buffer, grid_uv_buffer, grid_index_buffer, grid_index_len, grid_bind_group, rowshift_bind_group, grid_uv_staging_belt, grid_uv_staging_offset, grid_uv_staging_size, target_views, readback_storage_buffers, tap_view, readback_bind_groups, readback_buffers, readback_buffer_send, readback_buffer_recv) = generate_resize(size, &device, &queue, &surface, swapchain_format, &swapchain_capabilities, &diagonal_vertex_buffer, &diagonal_index_buffer, diagonal_index_len, &diagonal_render_pipeline, &grid_bind_group_layout, &default_sampler, &grid_uniform_buffer, &rowshift_bind_group_layout, &rowshift_uniform_buffer, &readback_bind_group_layout, &readback_uniform_buffers, readback_len, precision, visuals.tiles, &mut grid_rng);
+                        blur_size = size;
+                        blur_pending = true;
+                    }
+                    if blur_pending {
+                        (target_uniform_buffers, target_bind_groups) = generate_blur_chain(blur_size, diagonal_texture_side, &device, &target_bind_group_layout, &default_sampler, &target_views, &visuals);
+                    }
+
                     let mut encoder =
                             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
@@ -547,12 +770,13 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                         // Time frame is drawn at, for animation purposes
                         let grid_current = Instant::now();
                         // Time since last rowshift (in % of time to next rowshift)
-                        let mut grid_time_offset = grid_current.duration_since(grid_last_reset).as_secs_f32()*GRID_ANIMATE_SPEED + grid_last_reset_overflow;
+                        let mut grid_time_offset = grid_current.duration_since(grid_last_reset).as_secs_f32()*visuals.speed + grid_last_reset_overflow;
                         // Time-in-% is more than 100%
                         if grid_time_offset > 1. {
                             grid_time_offset %=  1.; // FIXME: What if it's more than 2?
                             grid_last_reset = grid_current;
                             grid_last_reset_overflow = grid_time_offset;
+                            grid_rows += 1;
 
                             // Begin this frame with a rowshift compute pass
                             {
@@ -566,7 +790,20 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                             // If this were JavaScript we'd map a temp buffer here, but instead the staging belt maps one for us.
                             {
                                 let mut mapped_bytes = grid_uv_staging_belt.write_buffer(&mut encoder, &grid_uv_buffer, grid_uv_staging_offset, grid_uv_staging_size, &device);
-                                random_uv_push(bytemuck::cast_slice_mut::<u8, f32>(mapped_bytes.deref_mut()));
+                                let grid_uv = bytemuck::cast_slice_mut::<u8, f32>(mapped_bytes.deref_mut());
+                                // If we're listening to something, it decides how likely each column is to flip
+                                let odds = match input.as_mut() {
+                                    Some(input) => input.flip_odds(diagonal_texture_count_x as usize),
+                                    None => Vec::new()
+                                };
+                                random_uv_push(grid_uv, &mut grid_rng, &odds);
+
+                                // Tell the outside world: one 0 or 1 per tile, 1 if flipped (its first U is 1, see random_uv_push)
+                                if let Some(ref osc) = listeners.osc {
+                                    let mut args = vec![OscArg::Int(grid_rows as i32)];
+                                    args.extend(grid_uv.chunks(8).map(|tile| OscArg::Int((tile[0] == 1.) as i32)));
+                                    osc.send("/row", &args);
+                                }
                             }
                             grid_uv_staging_belt.finish();
                         }
@@ -585,6 +822,42 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                         .texture
                         .create_view(&wgpu::TextureViewDescriptor::default());
 
+                    // Real quick see if we have any readback buffers returned
+                    while let Ok(readback_buffer) = readback_buffer_recv.try_recv() {
+                        readback_buffers.push(readback_buffer);
+                    }
+                    // Don't bother with readback if audio is already busy, or muted, unless MIDI or OSC wants it
+                    // (Every voice has to have room, so they stay in step)
+                    let audio_wants_readback = listeners.audio_frame_sends.iter().all(|send| !send.is_full()) && !audio_controls.muted();
+                    let readback_buffer: Option<Arc<wgpu::Buffer>> =
+                        if audio_wants_readback || listeners.midi.is_some() || listeners.osc.is_some() {
+                            readback_buffers.pop()
+                        } else { None }; // else { println!("READBACK DROPPED"); } // Uncomment to debug AUDIO_READBACK_BUFFER_MAX_INFLIGHT
+                    // Blur passes done when the row is read back, or None to read it after thresholding
+                    let tap_stage = visuals.tap.stage(visuals.blur_passes);
+
+                    // Move the scan heads (and their markers) to where they are now
+                    let scan_time = audio_epoch.elapsed().as_secs_f32();
+                    for (head, uniform_buffer) in heads.iter().zip(&readback_uniform_buffers) {
+                        queue.write_buffer(uniform_buffer, 0, bytemuck::cast_slice(&head.uniform(scan_time, readback_len)));
+                    }
+                    if markers {
+                        let window_size = [frame.texture.width() as f32, frame.texture.height() as f32];
+                        let vertices: Vec<[f32; 2]> = heads.iter().flat_map(|head| head.marker_vertices(scan_time, window_size, SCAN_MARKER_WIDTH)).collect();
+                        queue.write_buffer(&marker_vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+                    }
+
+                    // Read back each head's row for audio, from whichever bind group holds the stage we're tapping
+                    // (view_idx, as in readback_bind_groups), into its readback storage buffer. This has to happen before later passes draw over the stage.
+                    let readback_draw = |encoder: &mut wgpu::CommandEncoder, view_idx: usize| {
+                        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None, timestamp_writes: None });
+                        cpass.set_pipeline(&readback_pipeline);
+                        for bind_groups in &readback_bind_groups {
+                            cpass.set_bind_group(0, &bind_groups[view_idx], &[]);
+                            cpass.dispatch_workgroups(readback_workgroups, 1, 1);
+                        }
+                    };
+
                     // Initial draw of grid
                     {
                         let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -601,53 +874,53 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                         rpass.set_pipeline(&render_pipeline);
                         rpass.set_vertex_buffer(0, grid_vertex_buffer.slice(..));
                         rpass.set_vertex_buffer(1, grid_uv_buffer.slice(..));
-                        rpass.set_index_buffer(grid_index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+                        rpass.set_index_buffer(grid_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
                         rpass.set_bind_group(0, &grid_bind_group, &[]);
                         rpass.draw_indexed(0..grid_index_len, 0, 0..1);
                     }
+                    if readback_buffer.is_some() && tap_stage == Some(0) {
+                        readback_draw(&mut encoder, 0);
+                    }
 
                     // Postprocessing passes
-                    for stage in 0..TARGET_PASSES {
+                    for stage in 0..visuals.blur_passes {
                         // All stages do one dimension in a separable blur-- except the last, which blur-then-thresholds.
-                        let final_stage = stage == TARGET_PASSES-1;
-                        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                            label: None,
-                            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                                view: if final_stage {
-                                        &view
-                                    } else {
-                                        &target_views[(stage+1)%2]
-                                    },
-                                resolve_target: None,
-                                ops: DRAW_OPS,
-                            })],
-                            depth_stencil_attachment: None,
-                            occlusion_query_set: None,
-                            timestamp_writes: None
-                        });
-                        rpass.set_pipeline(if final_stage { &target_final_pipeline } else { &target_pipeline });
-                        rpass.set_vertex_buffer(0, target_vertex_buffer.slice(..));
-                        rpass.set_index_buffer(grid_index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-                        rpass.set_bind_group(0, &target_bind_groups[stage], &[]);
-                        rpass.draw_indexed(0..target_index_len, 0, 0..1);
+                        let final_stage = stage == visuals.blur_passes-1;
+                        {
+                            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                                label: None,
+                                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                                    view: if final_stage {
+                                            &view
+                                        } else {
+                                            &target_views[(stage+1)%2]
+                                        },
+                                    resolve_target: None,
+                                    ops: DRAW_OPS,
+                                })],
+                                depth_stencil_attachment: None,
+                                occlusion_query_set: None,
+                                timestamp_writes: None
+                            });
+                            rpass.set_pipeline(if final_stage { &target_final_pipeline } else { &target_pipeline });
+                            rpass.set_vertex_buffer(0, target_vertex_buffer.slice(..));
+                            rpass.set_index_buffer(grid_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                            rpass.set_bind_group(0, &target_bind_groups[stage], &[]);
+                            rpass.draw_indexed(0..target_index_len, 0, 0..1);
+                        }
+                        if readback_buffer.is_some() && tap_stage == Some(stage+1) {
+                            readback_draw(&mut encoder, (stage+1)%2);
+                        }
                     }
 
-                    // Real quick see if we have any readback buffers returned
-                    while let Ok(readback_buffer) = readback_buffer_recv.try_recv() {
-                        readback_buffers.push(readback_buffer);
-                    }
-                    // Don't bother with readback if audio is already busy
-                    let mut readback_buffer: Option<Arc<wgpu::Buffer>> = None; 
-                    if !audio_chunk_send.is_full() { 
-                        readback_buffer = readback_buffers.pop();
-                        if let Some(ref readback_buffer) = readback_buffer {
-                            // Read back final row for audio
-                            // Draw final row into 1-pixel-high texture:
+                    if let Some(ref readback_buffer) = readback_buffer {
+                        // Tapping the thresholded image: draw the last pass again, somewhere we can read it from
+                        if tap_stage.is_none() {
                             {
                                 let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                                     label: None,
                                     color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                                        view: &readback_view,
+                                        view: &tap_view,
                                         resolve_target: None,
                                         ops: DRAW_OPS,
                                     })],
@@ -655,31 +928,36 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                                     occlusion_query_set: None,
                                     timestamp_writes: None
                                 });
-                                rpass.set_pipeline(&readback_pipeline);
+                                rpass.set_pipeline(&target_tap_pipeline);
                                 rpass.set_vertex_buffer(0, target_vertex_buffer.slice(..));
-                                rpass.set_index_buffer(grid_index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-                                rpass.set_bind_group(0, &readback_bind_group, &[]);
+                                rpass.set_index_buffer(grid_index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                                rpass.set_bind_group(0, &target_bind_groups[visuals.blur_passes-1], &[]);
                                 rpass.draw_indexed(0..target_index_len, 0, 0..1);
                             }
+                            readback_draw(&mut encoder, 2);
+                        }
+
+                        let row_bytes = precision.row_bytes(readback_len) as u64;
+                        for (idx, storage_buffer) in readback_storage_buffers.iter().enumerate() {
+                            encoder.copy_buffer_to_buffer(storage_buffer, 0, readback_buffer, idx as u64*row_bytes, row_bytes);
+                        }
+                    }
 
-                            encoder.copy_texture_to_buffer(
-                                wgpu::ImageCopyTextureBase {
-                                    texture: &readback_texture,
-                                    mip_level:0,
-                                    origin: wgpu::Origin3d { x:0,y:0,z:0 },
-                                    aspect: wgpu::TextureAspect::All
-                                },
-                                wgpu::ImageCopyBuffer {
-                                    buffer: &readback_buffer,
-                                    layout: wgpu::ImageDataLayout {
-                                        offset:0,
-                                        bytes_per_row:None, // Not required, one row.
-                                        rows_per_image:None, // Not required, texture not cubic.
-                                    }
-                                },
-                                wgpu::Extent3d {width:AUDIO_READBACK_BUFFER_LEN as u32, height:1, depth_or_array_layers:1}
-                            );
-                        } // else { println!("READBACK DROPPED"); } // Uncomment to debug AUDIO_READBACK_BUFFER_MAX_INFLIGHT
+                    if markers {
+                        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                            label: None,
+                            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                                view: &view,
+                                resolve_target: None,
+                                ops: wgpu::Operations { load: wgpu::LoadOp::Load, store: wgpu::StoreOp::Store },
+                            })],
+                            depth_stencil_attachment: None,
+                            occlusion_query_set: None,
+                            timestamp_writes: None
+                        });
+                        rpass.set_pipeline(&marker_pipeline);
+                        rpass.set_vertex_buffer(0, marker_vertex_buffer.slice(..));
+                        rpass.draw(0..heads.len() as u32*6, 0..1);
                     }
 
                     // Done
@@ -691,31 +969,34 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                         // Clone all Arcs that will be captured by the closure below
                         let readback_buffer = readback_buffer.clone();
                         let readback_buffer_send = readback_buffer_send.clone();
-                        let audio_chunk_send = audio_chunk_send.clone();
-                        let (fft, fft_in, fft_out) = (fft.clone(), fft_in.clone(), fft_out.clone());
+                        let readback_row_send = readback_row_send.clone();
+                        let listeners = listeners.clone();
+                        let audio_stats = audio_stats.clone();
+                        // Stamp the frame with the time it was drawn, not the time the map completes
+                        let readback_time = audio_epoch.elapsed().as_secs_f64();
+                        let readback_row = grid_rows;
 
                         // The WebGPU spec says this promise resolves successfully only "after the completion of currently-enqueued operations that use 'this'", so this doubles as an on_submitted_work_done for these purposes.
                         slice.map_async(wgpu::MapMode::Read, move |result| {
                             if let Ok(()) = result {
                                 let slice = readback_buffer.slice(..);
-                                let (mut fft_in, mut fft_out) = (fft_in.borrow_mut(), fft_out.borrow_mut());
-
-                                let row = slice.get_mapped_range();
-                                let mut rng = rand::thread_rng(); // FIXME precompute this
-                                fft_in[0] = Default::default(); // Zero
-                                fft_in[AUDIO_READBACK_BUFFER_LEN-1] = Default::default(); // Zero
-                                for idx in 0..(AUDIO_READBACK_BUFFER_LEN-1) {
-                                    let phase = rng.gen::<f64>() * 2. * std::f64::consts::PI;
-                                    let ampl = 1. - row[idx] as f64/0xFF as f64;
-                                    fft_in[idx+1] = realfft::num_complex::Complex { re:phase.cos()*ampl, im:phase.sin()*ampl };
-                                }
-                                fft.process(&mut fft_in, &mut fft_out).unwrap();
-                                // We're done except we want f32s
-                                let chunk:AudioChunk = array::from_fn(|idx| {
-                                    (fft_out[idx]*fft_window[idx]) as f32 / 256.0 /* DIVISOR IS ARBITRARY FIXME */
+                                let bytes = slice.get_mapped_range();
+                                // Just copy the row out; what's done with it happens on the readback thread, where there is one
+                                let row = Box::new(ReadbackRow {
+                                    row: readback_row,
+                                    time: readback_time,
+                                    to_audio: audio_wants_readback,
+                                    precision,
+                                    len: readback_len,
+                                    bytes: bytes.to_vec(),
                                 });
-                                let result = audio_chunk_send.try_send(Box::new(chunk));
-                                if let Err(e) = result { println!("DROP AUDIO CHUNK {}", e); }
+                                drop(bytes);
+                                match readback_row_send {
+                                    Some(readback_row_send) => if readback_row_send.try_send(row).is_err() {
+                                        audio_stats.dropped_rows.fetch_add(1, Ordering::Relaxed);
+                                    },
+                                    None => listeners.deliver(&row)
+                                }
                             }
                             readback_buffer.unmap();
                             // Drop readback buffer in channel so it can be returned to pool.
@@ -730,6 +1011,28 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
                 WindowEvent::CloseRequested => {
                     target.exit();
                 }
+                WindowEvent::KeyboardInput { event: KeyEvent { logical_key: Key::Character(key), state: ElementState::Pressed, repeat: false, .. }, .. } => {
+                    match key.as_str() {
+                        "r" | "R" => {
+                            let recording = !audio_controls.recording();
+                            audio_controls.set_recording(recording);
+                            println!("Recording {}", if recording { "on" } else { "off" });
+                        },
+                        "m" | "M" => {
+                            let muted = !audio_controls.muted();
+                            audio_controls.set_muted(muted);
+                            println!("{}", if muted { "Muted" } else { "Unmuted" });
+                        },
+                        "+" | "=" | "-" => {
+                            const VOLUME_STEP_DB:f32 = 3.;
+                            let gain_db = (audio_controls.gain_db() + if key.as_str() == "-" { -VOLUME_STEP_DB } else { VOLUME_STEP_DB })
+                                .clamp(VOLUME_DB_MIN, VOLUME_DB_MAX);
+                            audio_controls.set_gain_db(gain_db);
+                            println!("Volume {} dB", gain_db);
+                        },
+                        _ => {}
+                    }
+                }
                 _ => {}
             }
             // The winit docs recommend doing your "state update" in MainEventsCleared and your draw triggering/logic here.
@@ -742,20 +1045,112 @@ async fn run(event_loop: EventLoop<()>, window: Window, audio_chunk_send: crossb
 }
 
 fn main() {
+    #[cfg(not(target_arch = "wasm32"))]
+    let settings = match Settings::from_args(std::env::args().skip(1)) {
+        Ok(settings) => settings,
+        Err(e @ SettingsError::Help) => { print!("{}", e); return },
+        Err(e) => { eprintln!("{}", e); std::process::exit(1) }
+    };
+    #[cfg(target_arch = "wasm32")]
+    let settings = Settings::default();
+
+    if settings.list_devices {
+        crate::audio::audio_list();
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
     let window = winit::window::Window::new(&event_loop).unwrap();
 
     // Initialize audio before window
-    const AUDIO_CHUNK_MAX_INFLIGHT: usize = 3;
-    // Use sync_channel to prevent unlimited buildup
-    let (audio_chunk_send, audio_chunk_recv) = crossbeam_channel::bounded::<Box<AudioChunk>>(AUDIO_CHUNK_MAX_INFLIGHT);
+    // The audio thread drains this every hop, so it only needs to cover a few frames at high refresh rates
+    const AUDIO_FRAME_MAX_INFLIGHT: usize = 8;
+    // Use sync_channel to prevent unlimited buildup. One per scan head, each feeding its own voice.
+    let (audio_frame_sends, audio_frame_recvs): (Vec<_>, Vec<_>) = settings.heads().iter()
+        .map(|_| crossbeam_channel::bounded::<Box<AudioFrame>>(AUDIO_FRAME_MAX_INFLIGHT))
+        .unzip();
+    // Played frames come back through here to be reused, so the audio thread never frees one
+    let frame_pool = FramePool::new(audio_frame_sends.len(), AUDIO_FRAME_MAX_INFLIGHT);
     
-    let audio = crate::audio::audio_spawn(audio_chunk_recv);
+    let audio_stats = Arc::new(AudioStats::default());
+    let audio_controls = Arc::new(AudioControls::new(settings.volume_db, settings.mute, settings.record.enabled));
+    let _audio = match crate::audio::audio_spawn(&settings.audio_backend, &settings, audio_stats.clone(), audio_controls.clone(), &audio_frame_recvs, &frame_pool) {
+        Ok(output) => {
+            println!("Boot");
+            Some(output)
+        },
+        // If the user asked for a particular backend, device or config, not getting it is fatal
+        Err(e) if settings.audio_backend != AudioBackend::Cpal || !settings.audio_device.is_default() || matches!(e, AudioError::GrainFile(..)) => {
+            eprintln!("Failure: {}", e);
+            std::process::exit(1);
+        },
+        // Otherwise keep synthesizing, just into the void, so the audio path behaves the same with or without a device
+        Err(e) => {
+            println!("Failure: {}; continuing without sound", e);
+            crate::audio::audio_spawn(&AudioBackend::Null, &settings, audio_stats.clone(), audio_controls.clone(), &audio_frame_recvs, &frame_pool).ok()
+        }
+    };
+
+    // There's nowhere to write a file on the web
+    let midi = match &settings.midi.path {
+        Some(_) if !cfg!(target_arch = "wasm32") => {
+            Some(Arc::new(Mutex::new(MidiRecorder::new(settings.midi.clone(), settings.synth.sample_rate, settings.synth.fft_len, settings.visuals.speed))))
+        },
+        _ => None
+    };
+
+    // Nor any UDP
+    let osc = match &settings.osc_out {
+        Some(target) if !cfg!(target_arch = "wasm32") => match OscSender::new(target) {
+            Ok(sender) => {
+                println!("Sending OSC to {}", target);
+                Some(Arc::new(sender))
+            },
+            Err(e) => { eprintln!("Couldn't send OSC to {}: {}", target, e); std::process::exit(1) }
+        },
+        _ => None
+    };
+
+    // Live control over OSC. Also no UDP on the web.
+    let (control_send, control_recv) = crossbeam_channel::unbounded::<Control>();
+    let _osc_listener = match settings.osc_in {
+        Some(port) if !cfg!(target_arch = "wasm32") => match crate::osc::osc_listen(port, control_send) {
+            Ok(thread) => {
+                println!("Listening for OSC on port {}", port);
+                Some(thread)
+            },
+            Err(e) => { eprintln!("Couldn't listen for OSC on port {}: {}", port, e); std::process::exit(1) }
+        },
+        _ => None
+    };
+
+    // Audio-reactive input needs threads or an input stream, neither of which we set up on the web
+    let input = if cfg!(target_arch = "wasm32") { None } else {
+        match crate::input::input_spawn(&settings.input, &settings.audio_device.host) {
+            Ok(input) => input,
+            Err(e) => { eprintln!("Couldn't listen to input: {}", e); std::process::exit(1) }
+        }
+    };
+
+    // Rows read back from the screen are handed on from a thread of their own, except on the web, which has no threads
+    let listeners = ReadbackListeners { audio_frame_sends, frame_pool, midi: midi.clone(), osc };
+    let (readback_row_send, _readback_thread) = if cfg!(target_arch = "wasm32") { (None, None) } else {
+        let (readback_row_send, readback_row_recv) = crossbeam_channel::bounded::<Box<ReadbackRow>>(READBACK_ROW_MAX_INFLIGHT);
+        (Some(readback_row_send), Some(crate::readback::readback_spawn(listeners.clone(), readback_row_recv)))
+    };
+
+    let context = RunContext { listeners, readback_row_send, audio_controls, audio_stats: audio_stats.clone(), readback_len: settings.synth.bins(), precision: settings.precision, heads: settings.heads(), markers: settings.markers, control_recv, input };
 
     #[cfg(not(target_arch = "wasm32"))]
     {
         env_logger::init();
-        pollster::block_on(run(event_loop, window, audio_chunk_send));
+        pollster::block_on(run(event_loop, window, settings.visuals.clone(), context));
+        audio_stats.report();
+        if let Some(midi) = midi {
+            if let Err(e) = midi.lock().unwrap().finish() {
+                println!("Couldn't write MIDI file: {}", e);
+            }
+        }
     }
     #[cfg(target_arch = "wasm32")]
     {
@@ -770,6 +1165,6 @@ fn main() {
                     .ok()
             })
             .expect("couldn't append canvas to document body");
-        wasm_bindgen_futures::spawn_local(run(event_loop, window, audio_chunk_send));
+        wasm_bindgen_futures::spawn_local(run(event_loop, window, settings.visuals, context));
     }
 }
@@ -0,0 +1,211 @@
+// Turn the readback row into notes, and save them as a Standard MIDI File
+
+use std::path::{Path, PathBuf};
+
+// Ticks per quarter note
+const MIDI_PPQ:u16 = 480;
+
+#[derive(Debug, Clone)]
+pub struct MidiConfig {
+    pub path: Option<PathBuf>, // Record to here; None to not record
+    pub note_length: f64,      // Length of one row shift, as a fraction of a whole note
+    pub threshold: f32,        // A pitch band sounds while its amplitude (0..1, as the synth plays it: dark is loud) is above this
+    pub tempo: Option<f64>,    // Beats per minute; None to follow the speed rows shift on screen, as it changes
+}
+
+impl Default for MidiConfig {
+    fn default() -> Self {
+        MidiConfig { path: None, note_length: 1./16., threshold: 0.5, tempo: None }
+    }
+}
+
+// Records note events as the screen scrolls. Each row shift is one step: a note starts on the first row its pitch band
+// is louder than the threshold, and ends on the first row it isn't. Levels are spectrum amplitudes, so dark is loud.
+pub struct MidiRecorder {
+    config: MidiConfig,
+    note_of_bin: Vec<Option<u8>>, // MIDI note nearest each spectrum bin's frequency
+    row: Option<u64>,             // Row levels is collecting for
+    levels: [f32; 128],           // Loudest each band has been during the row
+    sounding: [bool; 128],
+    events: Vec<(u64, [u8; 3])>,  // Tick, message
+    speeds: Vec<(u64, f32)>,      // Row, and the row shifts per second from it on
+    last_row: u64,
+}
+
+impl MidiRecorder {
+    // sample_rate and fft_len are the synthesizer's, which decide what frequency each bin actually plays at.
+    // speed is the row shifts per second the screen starts out scrolling at.
+    pub fn new(config: MidiConfig, sample_rate: u32, fft_len: usize, speed: f32) -> Self {
+        // The row is laid out along the shader's readback_curve, but by the time it's a spectrum, index idx is simply FFT bin idx+1
+        // (see Resynth::hop). Several bins can land in one note's band, and low notes may get none.
+        let note_of_bin = (0..fft_len/2).map(|idx| {
+            let frequency = (idx+1) as f64 * sample_rate as f64 / fft_len as f64;
+            let note = (69. + 12.*(frequency/440.).log2()).round();
+            if (0. ..=127.).contains(&note) { Some(note as u8) } else { None }
+        }).collect();
+        MidiRecorder { config, note_of_bin, row: None, levels: [0.; 128], sounding: [false; 128], events: Default::default(), speeds: vec![(0, speed)], last_row: 0 }
+    }
+
+    // The screen scrolls at a new speed from row on. Rows must not go backward.
+    pub fn set_speed(&mut self, row: u64, speed: f32) {
+        if self.speeds.last().is_some_and(|&(last, _)| last == row) {
+            self.speeds.pop();
+        }
+        self.speeds.push((row, speed));
+    }
+
+    fn ticks_per_row(&self) -> u64 {
+        ((self.config.note_length * 4. * MIDI_PPQ as f64).round() as u64).max(1)
+    }
+
+    // A spectrum read back while row was the newest row on screen. Rows must not go backward.
+    pub fn push(&mut self, row: u64, spectrum: &[f32]) {
+        if self.row.is_some_and(|current| current != row) {
+            self.commit();
+        }
+        self.row = Some(row);
+        for (idx, &level) in spectrum.iter().enumerate() {
+            if let Some(note) = self.note_of_bin[idx] {
+                let band = &mut self.levels[note as usize];
+                *band = band.max(level);
+            }
+        }
+    }
+
+    // Compare the row just finished against what's sounding
+    fn commit(&mut self) {
+        let Some(row) = self.row.take() else { return };
+        let tick = row * self.ticks_per_row();
+        // Offs first, so a note that stops and starts on the same tick restarts cleanly
+        for note in 0..128u8 {
+            if self.sounding[note as usize] && self.levels[note as usize] <= self.config.threshold {
+                self.events.push((tick, [0x80, note, 0x40]));
+                self.sounding[note as usize] = false;
+            }
+        }
+        for note in 0..128u8 {
+            let level = self.levels[note as usize];
+            if !self.sounding[note as usize] && level > self.config.threshold {
+                let loudness = (level - self.config.threshold) / (1. - self.config.threshold).max(f32::EPSILON);
+                let velocity = (1. + loudness*126.).round().clamp(1., 127.) as u8;
+                self.events.push((tick, [0x90, note, velocity]));
+                self.sounding[note as usize] = true;
+            }
+        }
+        self.levels = [0.; 128];
+        self.last_row = row;
+    }
+
+    // Close any notes still sounding and write the file
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.commit();
+        let end = (self.last_row + 1) * self.ticks_per_row();
+        for note in 0..128u8 {
+            if self.sounding[note as usize] {
+                self.events.push((end, [0x80, note, 0x40]));
+                self.sounding[note as usize] = false;
+            }
+        }
+        let Some(path) = self.config.path.clone() else { return Ok(()) };
+        println!("Writing {} MIDI events to {}", self.events.len(), path.display());
+        self.write(&path)
+    }
+
+    // Tempo meta events: one at the start for a fixed tempo, or one for each speed change so it plays back at the
+    // speed it was seen
+    fn tempo_events(&self) -> Vec<(u64, Vec<u8>)> {
+        let quarter_seconds:Vec<(u64, f64)> = match self.config.tempo {
+            Some(bpm) => vec![(0, 60. / bpm)],
+            None => self.speeds.iter().map(|&(row, speed)|
+                (row * self.ticks_per_row(), 1. / (speed as f64 * self.config.note_length * 4.))
+            ).collect(),
+        };
+        quarter_seconds.into_iter().map(|(tick, seconds)| {
+            let tempo = ((seconds * 1e6).round() as u32).clamp(1, 0xFF_FFFF);
+            let mut event = vec![0xFF, 0x51, 0x03];
+            event.extend_from_slice(&tempo.to_be_bytes()[1..]);
+            (tick, event)
+        }).collect()
+    }
+
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        // A stable sort, so a tempo change goes ahead of the notes on its tick and they play at the new speed
+        let mut track_events = self.tempo_events();
+        track_events.extend(self.events.iter().map(|&(tick, message)| (tick, message.to_vec())));
+        track_events.sort_by_key(|&(tick, _)| tick);
+
+        let mut track:Vec<u8> = Default::default();
+        let mut last_tick = 0;
+        for (tick, message) in track_events {
+            write_var_len(&mut track, (tick - last_tick) as u32);
+            track.extend_from_slice(&message);
+            last_tick = tick;
+        }
+        write_var_len(&mut track, 0);
+        track.extend_from_slice(&[0xFF, 0x2F, 0x00]); // End of track
+
+        let mut file:Vec<u8> = Default::default();
+        file.extend_from_slice(b"MThd");
+        file.extend_from_slice(&6u32.to_be_bytes());
+        file.extend_from_slice(&0u16.to_be_bytes()); // Format 0: one track
+        file.extend_from_slice(&1u16.to_be_bytes());
+        file.extend_from_slice(&MIDI_PPQ.to_be_bytes());
+        file.extend_from_slice(b"MTrk");
+        file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        file.extend_from_slice(&track);
+        std::fs::write(path, file)
+    }
+}
+
+// MIDI's variable-length quantity: 7 bits per byte, most significant first, high bit set on all but the last
+fn write_var_len(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0u8; 5];
+    let mut count = 0;
+    let mut value = value;
+    loop {
+        bytes[count] = (value & 0x7F) as u8;
+        count += 1;
+        value >>= 7;
+        if value == 0 { break }
+    }
+    for idx in (0..count).rev() {
+        out.push(bytes[idx] | if idx > 0 { 0x80 } else { 0 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn var_len() {
+        for (value, bytes) in [(0, vec![0x00]), (0x40, vec![0x40]), (0x7F, vec![0x7F]), (0x80, vec![0x81, 0x00]),
+                               (0x2000, vec![0xC0, 0x00]), (0x3FFF, vec![0xFF, 0x7F]), (0x4000, vec![0x81, 0x80, 0x00]),
+                               (0x0FFF_FFFF, vec![0xFF, 0xFF, 0xFF, 0x7F])] {
+            let mut out = Vec::new();
+            write_var_len(&mut out, value);
+            assert_eq!(out, bytes, "{:#x}", value);
+        }
+    }
+
+    // The track chunk's length covers exactly what follows it, tempo changes and all
+    #[test]
+    fn track_length() {
+        let path = std::env::temp_dir().join(format!("wgpu-hello-{}-track.mid", std::process::id()));
+        let config = MidiConfig { path: Some(path.clone()), ..Default::default() };
+        let mut recorder = MidiRecorder::new(config, 48000, 2048, 1.5);
+        recorder.push(0, &[1.; 1024]);
+        recorder.set_speed(3, 3.);
+        recorder.push(5, &[0.; 1024]);
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(&data[..4], b"MThd");
+        assert_eq!(&data[14..18], b"MTrk");
+        let track_len = u32::from_be_bytes(data[18..22].try_into().unwrap()) as usize;
+        assert_eq!(data.len(), 22 + track_len);
+        assert!(data.ends_with(&[0xFF, 0x2F, 0x00]));
+        assert_eq!(data.windows(3).filter(|bytes| bytes == &[0xFF, 0x51, 0x03]).count(), 2);
+    }
+}
@@ -0,0 +1,295 @@
+// Just enough Open Sound Control to talk to lighting rigs and other synths over UDP, and to be controlled by them
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::Ordering;
+use web_time::Duration;
+
+use crate::constants::*;
+use crate::control::{AudioTap, Control, ThresholdMode};
+use crate::worker::WorkerThread;
+
+// One argument of a message. Strings and blobs are borrowed, from the message being sent or the packet being read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OscArg<'a> {
+    Int(i32),
+    Float(f32),
+    Str(&'a str),
+    Blob(&'a [u8]),
+}
+
+impl OscArg<'_> {
+    // Controllers disagree about whether to send ints or floats, so take either
+    fn as_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(value) => Some(value as f32),
+            OscArg::Float(value) => Some(value),
+            _ => None
+        }
+    }
+}
+
+// OSC pads everything to a multiple of 4 bytes
+fn pad(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+// Strings always get at least one terminating zero
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    pad(out);
+}
+
+pub fn osc_encode(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut out:Vec<u8> = Default::default();
+    write_str(&mut out, address);
+    let tags:String = std::iter::once(',').chain(args.iter().map(|arg| match arg {
+        OscArg::Int(_) => 'i',
+        OscArg::Float(_) => 'f',
+        OscArg::Str(_) => 's',
+        OscArg::Blob(_) => 'b',
+    })).collect();
+    write_str(&mut out, &tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(value) => out.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Float(value) => out.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Str(value) => write_str(&mut out, value),
+            OscArg::Blob(value) => {
+                out.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                out.extend_from_slice(value);
+                pad(&mut out);
+            },
+        }
+    }
+    out
+}
+
+// Fire-and-forget sender. Never blocks: if the network can't keep up, messages are dropped.
+pub struct OscSender {
+    socket: UdpSocket,
+}
+
+impl OscSender {
+    // target is "host:port"
+    pub fn new(target: &str) -> std::io::Result<OscSender> {
+        let target = target.to_socket_addrs()?.next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("No address for '{}'", target)))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(target)?;
+        socket.set_nonblocking(true)?;
+        Ok(OscSender { socket })
+    }
+
+    pub fn send(&self, address: &str, args: &[OscArg]) {
+        self.socket.send(&osc_encode(address, args)).ok();
+    }
+}
+
+// Reads OSC's padded types off the front of a packet
+struct OscReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> OscReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() { return None }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest.get(len.next_multiple_of(4) - len..).unwrap_or_default();
+        Some(taken)
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.data.iter().position(|&byte| byte == 0)?;
+        let taken = self.take(len+1)?;
+        std::str::from_utf8(&taken[..len]).ok()
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
+
+// Every message in a packet, looking inside bundles (whose time tags we ignore; everything happens as it arrives).
+// Anything malformed, or with argument types we don't know, is skipped.
+pub fn osc_decode<'a>(packet: &'a [u8], out: &mut Vec<(&'a str, Vec<OscArg<'a>>)>) {
+    let mut reader = OscReader { data: packet };
+    if packet.starts_with(b"#bundle\0") {
+        reader.take(16); // "#bundle" and time tag
+        while let Some(len) = reader.u32() {
+            let Some(element) = reader.take(len as usize) else { break };
+            osc_decode(element, out);
+        }
+        return;
+    }
+
+    let Some(address) = reader.str() else { return };
+    let tags = reader.str().unwrap_or(","); // Very old senders leave out the type tags
+    let mut args:Vec<OscArg> = Default::default();
+    for tag in tags.chars().skip(1) {
+        let arg = match tag {
+            'i' => reader.u32().map(|value| OscArg::Int(value as i32)),
+            'f' => reader.u32().map(|value| OscArg::Float(f32::from_bits(value))),
+            's' => reader.str().map(OscArg::Str),
+            'b' => reader.u32().and_then(|len| reader.take(len as usize)).map(OscArg::Blob),
+            _ => None
+        };
+        let Some(arg) = arg else { return };
+        args.push(arg);
+    }
+    out.push((address, args));
+}
+
+pub const OSC_ADDRESSES:&str = "\
+    /speed ROWS            Row shifts per second
+    /stroke WIDTH          Width of the diagonals, as a fraction of a tile (regenerates the grid)
+    /tiles N               Tiles across the shorter side of the window, 1 to 200 (regenerates the grid)
+    /seed N                Reseed the random tile flips
+    /threshold LOW [HIGH]  Blur levels between LOW and HIGH form the blobs' soft edges (HIGH defaults to LOW+0.1)
+    /threshold/mode MODE   soft (ramp between the edges) or hard (cut off between them)
+    /threshold/invert 0|1  Swap blobs and background
+    /blur/passes N         Number of blur passes, 1 to 32
+    /blur/radius PX        Distance the first pair of blur passes samples at
+    /blur/growth FACTOR    How much further each pair of blur passes reaches than the last
+    /tap STAGE             What the audio hears: grid, final, threshold, or a number of blur passes
+    /volume DB             Master volume
+    /mute 0|1
+    /record 0|1            Record audio to WAV
+";
+
+// Turn one message into a Control, if it's one we know. Numbers may be sent as ints or floats; NaNs and
+// infinities are ignored, since they'd stick in everything downstream of them.
+fn osc_control(address: &str, args: &[OscArg]) -> Option<Control> {
+    let number = |idx: usize| args.get(idx).and_then(OscArg::as_f32).filter(|number| number.is_finite());
+    let control = match address {
+        "/speed" => Control::Speed(number(0)?.max(GRID_ANIMATE_SPEED_MIN)),
+        "/stroke" => Control::Stroke(number(0)?.clamp(STROKE_MIN, STROKE_MAX)),
+        "/tiles" => Control::Tiles((number(0)?.round() as u32).clamp(1, TILES_MAX)),
+        "/seed" => Control::Seed(number(0)?.round() as u64),
+        "/threshold" => {
+            // smoothstep needs the low edge strictly below the high one
+            let low = number(0)?;
+            Control::Threshold(low, number(1).unwrap_or(low + 0.1).max(low + THRESHOLD_EDGE_MIN))
+        },
+        "/threshold/mode" => Control::ThresholdMode(match args.first()? {
+            OscArg::Str(name) => name.parse().ok()?,
+            _ => if number(0)? != 0. { ThresholdMode::Hard } else { ThresholdMode::Soft },
+        }),
+        "/threshold/invert" => Control::ThresholdInvert(number(0)? != 0.),
+        "/blur/passes" => Control::BlurPasses(number(0)?.round() as usize),
+        "/blur/radius" => Control::BlurRadius(number(0)?.clamp(0.01, 1000.)),
+        "/blur/growth" => Control::BlurGrowth(number(0)?.clamp(0.01, 100.)),
+        "/tap" => Control::Tap(match args.first()? {
+            OscArg::Str(name) => name.parse().ok()?,
+            _ => AudioTap::Blur(number(0)?.max(0.).round() as usize),
+        }),
+        "/volume" => Control::Volume(number(0)?.clamp(VOLUME_DB_MIN, VOLUME_DB_MAX)),
+        "/mute" => Control::Mute(number(0)? != 0.),
+        "/record" => Control::Record(number(0)? != 0.),
+        _ => return None
+    };
+    Some(control)
+}
+
+// How often the listener checks whether it should quit
+const OSC_POLL_INTERVAL:Duration = Duration::from_millis(100);
+
+// Listen for control messages (see OSC_ADDRESSES) on a UDP port, and forward them to the render loop
+pub fn osc_listen(port: u16, control_send: crossbeam_channel::Sender<Control>) -> std::io::Result<WorkerThread> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    socket.set_read_timeout(Some(OSC_POLL_INTERVAL))?;
+    Ok(WorkerThread::spawn("osc listener", move |quit| {
+        let mut packet = [0u8; 65536];
+        while !quit.load(Ordering::Relaxed) {
+            let Ok(len) = socket.recv(&mut packet) else { continue }; // Timeouts included
+            let mut messages:Vec<(&str, Vec<OscArg>)> = Default::default();
+            osc_decode(&packet[..len], &mut messages);
+            for (address, args) in messages {
+                match osc_control(address, &args) {
+                    Some(control) => { control_send.send(control).ok(); },
+                    None => println!("Unknown OSC message {} {:?}", address, args)
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(address: &str, args: &[OscArg]) {
+        let packet = osc_encode(address, args);
+        assert_eq!(packet.len() % 4, 0, "packet for {} isn't padded", address);
+        let mut messages = Vec::new();
+        osc_decode(&packet, &mut messages);
+        assert_eq!(messages, vec![(address, args.to_vec())]);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        round_trip("/row", &[]);
+        round_trip("/abc", &[OscArg::Int(-7), OscArg::Float(0.25)]); // Address exactly 4 bytes, so padded with 4 zeroes
+        round_trip("/threshold/mode", &[OscArg::Str("hard")]);
+        round_trip("/tap", &[OscArg::Str("")]);
+        for len in 0..9 {
+            let blob:Vec<u8> = (0..len).map(|idx| idx as u8 + 1).collect();
+            round_trip("/readback", &[OscArg::Int(42), OscArg::Float(1.5), OscArg::Blob(&blob), OscArg::Int(i32::MIN)]);
+        }
+    }
+
+    #[test]
+    fn decode_bundle() {
+        let first = osc_encode("/speed", &[OscArg::Float(2.)]);
+        let second = osc_encode("/mute", &[OscArg::Int(1)]);
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend_from_slice(&1u64.to_be_bytes()); // Time tag: immediately
+        for element in [&first, &second] {
+            packet.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        let mut messages = Vec::new();
+        osc_decode(&packet, &mut messages);
+        assert_eq!(messages, vec![("/speed", vec![OscArg::Float(2.)]), ("/mute", vec![OscArg::Int(1)])]);
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let packet = osc_encode("/readback", &[OscArg::Blob(&[1, 2, 3, 4, 5, 6, 7, 8])]);
+        for len in 0..packet.len() {
+            let mut messages = Vec::new();
+            osc_decode(&packet[..len], &mut messages);
+            assert!(messages.iter().all(|(_, args)| args.is_empty()), "truncated to {} bytes, decoded {:?}", len, messages);
+        }
+    }
+
+    #[test]
+    fn controls() {
+        assert_eq!(osc_control("/speed", &[OscArg::Int(3)]), Some(Control::Speed(3.)));
+        assert_eq!(osc_control("/speed", &[OscArg::Float(f32::NAN)]), None);
+        assert_eq!(osc_control("/volume", &[OscArg::Float(f32::INFINITY)]), None);
+        assert_eq!(osc_control("/volume", &[OscArg::Float(1000.)]), Some(Control::Volume(VOLUME_DB_MAX)));
+        assert_eq!(osc_control("/tiles", &[OscArg::Int(100000)]), Some(Control::Tiles(TILES_MAX)));
+        assert_eq!(osc_control("/threshold", &[OscArg::Float(0.7), OscArg::Float(0.2)]), Some(Control::Threshold(0.7, 0.7 + THRESHOLD_EDGE_MIN)));
+        assert_eq!(osc_control("/tap", &[OscArg::Str("grid")]), Some(Control::Tap(AudioTap::Grid)));
+        assert_eq!(osc_control("/nonsense", &[]), None);
+    }
+
+    // What OscSender puts on the wire, as a listener on this machine receives it
+    #[test]
+    fn send_to_local_listener() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let sender = OscSender::new(&listener.local_addr().unwrap().to_string()).unwrap();
+        let row = [0u8, 64, 128, 255, 7];
+        sender.send("/readback", &[OscArg::Int(12), OscArg::Float(0.5), OscArg::Blob(&row)]);
+
+        let mut packet = [0u8; 1024];
+        let len = listener.recv(&mut packet).unwrap();
+        let mut messages = Vec::new();
+        osc_decode(&packet[..len], &mut messages);
+        assert_eq!(messages, vec![("/readback", vec![OscArg::Int(12), OscArg::Float(0.5), OscArg::Blob(&row)])]);
+    }
+}
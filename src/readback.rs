@@ -0,0 +1,148 @@
+// Everything that happens to a row once it's been read back from the screen
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use web_time::Duration;
+
+use crate::midi::MidiRecorder;
+use crate::osc::{OscArg, OscSender};
+use crate::synth::{AudioFrame, FramePool};
+use crate::worker::WorkerThread;
+
+// Format of the offscreen textures the picture is blurred in, and so of the row read back
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Precision {
+    #[default]
+    Unorm8,  // 256 levels. The blur bands, and slow fades step audibly.
+    Float16,
+    Float32,
+}
+
+impl Precision {
+    pub fn format(self) -> wgpu::TextureFormat {
+        match self {
+            Precision::Unorm8 => wgpu::TextureFormat::R8Unorm,
+            Precision::Float16 => wgpu::TextureFormat::R16Float,
+            Precision::Float32 => wgpu::TextureFormat::R32Float,
+        }
+    }
+
+    pub fn texel_bytes(self) -> usize {
+        match self {
+            Precision::Unorm8 => 1,
+            Precision::Float16 => 2,
+            Precision::Float32 => 4,
+        }
+    }
+
+    // Bytes a row of len pixels takes, padded to whole 32-bit words as the readback shader writes them
+    pub fn row_bytes(self, len: usize) -> usize {
+        (len*self.texel_bytes()).next_multiple_of(4)
+    }
+
+    // Compute shader entry point that reads back rows in this format
+    pub fn readback_entry(self) -> &'static str {
+        match self {
+            Precision::Unorm8 => "readback_unorm8",
+            Precision::Float16 => "readback_float16",
+            Precision::Float32 => "readback_float32",
+        }
+    }
+
+    // Brightness (0..1) of each pixel in a row read back in this format
+    pub fn brightness(self, bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+        bytes.chunks_exact(self.texel_bytes()).map(move |texel| {
+            let value = match self {
+                Precision::Unorm8 => texel[0] as f32/0xFF as f32,
+                Precision::Float16 => f16_to_f32(u16::from_le_bytes([texel[0], texel[1]])),
+                Precision::Float32 => f32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]]),
+            };
+            value.clamp(0., 1.)
+        })
+    }
+}
+
+// Half-precision float bits to f32. Render output never holds infinities or NaNs, so those aren't worried about.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1. } else { 1. };
+    let exponent = ((bits >> 10) & 0x1F) as i32;
+    let mantissa = (bits & 0x3FF) as f32 / 1024.;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-14), // Subnormal
+        _ => (1. + mantissa) * 2f32.powi(exponent - 15),
+    }
+}
+
+// What every scan head saw, exactly as the GPU handed it over
+pub struct ReadbackRow {
+    pub row: u64,      // Row shifts so far when it was drawn
+    pub time: f64,     // When it was drawn, in seconds since the audio epoch
+    pub to_audio: bool, // Whether the synthesizer wanted this one (it may have been read back only for MIDI or OSC)
+    pub precision: Precision,
+    pub len: usize,     // Pixels per head
+    pub bytes: Vec<u8>, // Each head's row in turn, padded to precision.row_bytes(len). One texel per frequency bin: dark pixels are loud
+}
+
+impl ReadbackRow {
+    // The texels each head read, in head order
+    pub fn heads(&self) -> impl Iterator<Item = &[u8]> {
+        let texels = self.len*self.precision.texel_bytes();
+        self.bytes.chunks_exact(self.precision.row_bytes(self.len)).map(move |head| &head[..texels])
+    }
+}
+
+// Rows read back but not yet handed on. The render loop keeps at most a couple of readbacks in flight, so this
+// only fills if the readback thread stalls, and then rows are dropped.
+pub const READBACK_ROW_MAX_INFLIGHT:usize = 8;
+
+// How often the readback thread checks whether it should quit
+const READBACK_POLL_INTERVAL:Duration = Duration::from_millis(100);
+
+// Whoever wants to hear about rows
+#[derive(Clone)]
+pub struct ReadbackListeners {
+    pub audio_frame_sends: Vec<crossbeam_channel::Sender<Box<AudioFrame>>>, // One per scan head
+    pub frame_pool: FramePool, // Frames the audio side is done with, to fill again
+    pub midi: Option<Arc<Mutex<MidiRecorder>>>, // Hears the first head only
+    pub osc: Option<Arc<OscSender>>,
+}
+
+impl ReadbackListeners {
+    pub fn deliver(&self, row: &ReadbackRow) {
+        for (head, (bytes, audio_frame_send)) in row.heads().zip(&self.audio_frame_sends).enumerate() {
+            if let Some(osc) = &self.osc {
+                let address = if head == 0 { "/readback".to_string() } else { format!("/readback/{}", head) };
+                osc.send(&address, &[OscArg::Int(row.row as i32), OscArg::Float(row.time as f32), OscArg::Blob(bytes)]);
+            }
+            let mut frame = self.frame_pool.take(row.len);
+            frame.time = row.time;
+            frame.spectrum.clear();
+            frame.spectrum.extend(row.precision.brightness(bytes).map(|brightness| 1. - brightness));
+            if head == 0 {
+                if let Some(midi) = &self.midi {
+                    midi.lock().unwrap().push(row.row, &frame.spectrum);
+                }
+            }
+            if row.to_audio {
+                if let Err(e) = audio_frame_send.try_send(frame) {
+                    println!("DROP AUDIO FRAME {}", e);
+                    self.frame_pool.give(e.into_inner());
+                }
+            } else {
+                self.frame_pool.give(frame);
+            }
+        }
+    }
+}
+
+// Hand rows on from a thread of our own, so the map_async callback only has to copy bytes, and a slow listener
+// (a MIDI lock, a full network buffer) can never hold up device.poll or the render loop
+pub fn readback_spawn(listeners: ReadbackListeners, row_recv: crossbeam_channel::Receiver<Box<ReadbackRow>>) -> WorkerThread {
+    WorkerThread::spawn("readback", move |quit| {
+        while !quit.load(Ordering::Relaxed) {
+            if let Ok(row) = row_recv.recv_timeout(READBACK_POLL_INTERVAL) {
+                listeners.deliver(&row);
+            }
+        }
+    })
+}
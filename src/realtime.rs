@@ -0,0 +1,63 @@
+// Keeping the audio callback real-time safe: it must never wait on the allocator, so it mustn't allocate or free.
+// Debug builds count what it does anyway, and fail an assertion when the callback returns.
+
+#[cfg(debug_assertions)]
+mod checked {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        // Whether this thread is inside the audio callback, and allocations and frees it's made there.
+        // Const-initialized with no destructor, so reading them never allocates.
+        pub static INSIDE: Cell<bool> = const { Cell::new(false) };
+        pub static COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn note() {
+        if INSIDE.get() {
+            COUNT.set(COUNT.get() + 1);
+        }
+    }
+
+    // The system allocator, keeping count
+    pub struct CheckedAllocator;
+
+    unsafe impl GlobalAlloc for CheckedAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            note();
+            unsafe { System.alloc(layout) }
+        }
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            note();
+            unsafe { System.alloc_zeroed(layout) }
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            note();
+            unsafe { System.dealloc(ptr, layout) }
+        }
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            note();
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CheckedAllocator = CheckedAllocator;
+}
+
+// Run one audio callback's worth of work
+#[cfg(debug_assertions)]
+pub fn realtime<R>(body: impl FnOnce() -> R) -> R {
+    use checked::{COUNT, INSIDE};
+    COUNT.set(0);
+    INSIDE.set(true);
+    let result = body();
+    INSIDE.set(false);
+    debug_assert_eq!(COUNT.get(), 0, "Memory allocated or freed inside the audio callback");
+    result
+}
+
+#[cfg(not(debug_assertions))]
+pub fn realtime<R>(body: impl FnOnce() -> R) -> R {
+    body()
+}
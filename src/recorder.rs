@@ -0,0 +1,160 @@
+// Record what we play to WAV files, on a thread of its own so the audio callback never touches the disk
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use web_time::{Duration, Instant};
+
+use crate::audio::AudioControls;
+use crate::dither::DitherMode;
+use crate::wav::*;
+use crate::worker::WorkerThread;
+
+// Rewrite the WAV header this often, so a crash loses at most this much audio
+const RECORD_PATCH_INTERVAL:Duration = Duration::from_secs(1);
+
+// How often the writer thread wakes up to drain samples
+const RECORD_POLL_INTERVAL:Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct RecordConfig {
+    pub path: PathBuf,
+    pub max_bytes: Option<u64>,   // Start a new file once the current one is this big
+    pub max_seconds: Option<f64>, // Start a new file once the current one is this long
+    pub enabled: bool,            // Recording at startup (can be toggled later)
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        RecordConfig {
+            path: PathBuf::from("audio_log.wav"),
+            max_bytes: None,
+            max_seconds: None,
+            enabled: cfg!(feature = "audio_log"),
+        }
+    }
+}
+
+impl RecordConfig {
+    // The first file gets the path as given; files after a rotation or restart get a number
+    fn file_path(&self, idx: usize) -> PathBuf {
+        if idx == 0 { return self.path.clone() }
+        let stem = self.path.file_stem().map_or("audio_log".into(), |stem| stem.to_string_lossy());
+        let extension = self.path.extension().map_or("wav".into(), |extension| extension.to_string_lossy());
+        self.path.with_file_name(format!("{}-{}.{}", stem, idx, extension))
+    }
+}
+
+// Audio thread end. Never blocks; if the writer falls a full ring behind, frames are dropped.
+pub struct RecorderInput {
+    producer: rtrb::Producer<f32>,
+    controls: Arc<AudioControls>,
+    channels: usize, // 1 to record left and right mixed together, 2 for both
+}
+
+impl RecorderInput {
+    pub fn push(&mut self, frame: [f32; 2]) {
+        if self.controls.recording() {
+            let frame = if self.channels == 1 { [(frame[0] + frame[1]) / 2., 0.] } else { frame };
+            if let Ok(chunk) = self.producer.write_chunk_uninit(self.channels) {
+                chunk.fill_from_iter(frame);
+            }
+        }
+    }
+}
+
+struct Recorder {
+    config: RecordConfig,
+    controls: Arc<AudioControls>,
+    format: WavFormat,
+    dither: DitherMode,
+    channels: u16,
+    sample_rate: u32,
+    writer: Option<WavWriter>,
+    file_idx: usize,
+    last_patch: Instant,
+}
+
+impl Recorder {
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        if self.writer.is_none() {
+            let path = self.config.file_path(self.file_idx);
+            self.file_idx += 1;
+            println!("Recording to {}", path.display());
+            self.writer = Some(WavWriter::create(&path, self.format, self.channels, self.sample_rate, self.dither)?);
+            self.last_patch = Instant::now();
+        }
+        let Some(writer) = self.writer.as_mut() else { unreachable!() };
+        writer.write_samples(samples)?;
+
+        let full = self.config.max_bytes.is_some_and(|max| writer.file_len() >= max)
+            || self.config.max_seconds.is_some_and(|max| writer.duration() >= max);
+        if full {
+            self.finish()?; // Next write opens a new file
+        } else if self.last_patch.elapsed() >= RECORD_PATCH_INTERVAL {
+            writer.patch_header()?;
+            self.last_patch = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Ok(())
+        }
+    }
+
+    fn run(mut self, mut consumer: rtrb::Consumer<f32>, quit: Arc<AtomicBool>) {
+        let mut samples:Vec<f32> = Default::default();
+        loop {
+            // Check these before draining, so anything pushed before they changed gets written
+            let quitting = quit.load(Ordering::Relaxed) || consumer.is_abandoned();
+            let recording = self.controls.recording();
+
+            samples.clear();
+            if let Ok(chunk) = consumer.read_chunk(consumer.slots()) {
+                let (first, second) = chunk.as_slices();
+                samples.extend_from_slice(first);
+                samples.extend_from_slice(second);
+                chunk.commit_all();
+            }
+
+            let mut result = Ok(());
+            if !samples.is_empty() {
+                result = self.write(&samples);
+            }
+            if result.is_ok() && (quitting || !recording) {
+                result = self.finish();
+            }
+            if let Err(e) = result {
+                println!("Recording failed: {}", e);
+                self.writer = None;
+                self.controls.set_recording(false);
+            }
+
+            if quitting { break }
+            std::thread::sleep(RECORD_POLL_INTERVAL);
+        }
+    }
+}
+
+// Frames are stereo as they leave the output stage, and recorded in channels channels (1 or 2; see output_channels).
+// format and sample_rate should describe the device being played to.
+// Frames arrive before the device's dither, so integer formats get dithered afresh.
+// Dropping the returned thread finishes the current file.
+pub fn recorder_spawn(config: RecordConfig, controls: Arc<AudioControls>, format: WavFormat, dither: DitherMode, channels: u16, sample_rate: u32) -> (RecorderInput, WorkerThread) {
+    // One second of slack for the writer thread
+    let (producer, consumer) = rtrb::RingBuffer::new(sample_rate as usize * channels as usize);
+
+    let recorder = Recorder {
+        config, format, dither, channels, sample_rate,
+        controls: controls.clone(),
+        writer: None,
+        file_idx: 0,
+        last_patch: Instant::now(),
+    };
+    let thread = WorkerThread::spawn("recorder", move |quit| recorder.run(consumer, quit));
+
+    (RecorderInput { producer, controls, channels: channels as usize }, thread)
+}
@@ -0,0 +1,148 @@
+// Sample rate conversion, so the synthesizer can run at one rate whatever rate the device plays at
+
+// Zero crossings of the sinc kernel kept either side of the point being interpolated. More is sharper but slower.
+const RESAMPLE_ZERO_CROSSINGS:usize = 16;
+
+// Kernel values stored per input frame; values between are interpolated linearly
+const RESAMPLE_PHASES:usize = 512;
+
+// Kaiser window shape. 8.6 puts the stopband around -90 dB.
+const RESAMPLE_KAISER_BETA:f64 = 8.6;
+
+// Passband, as a fraction of the lower of the two Nyquist frequencies. The rest is the transition band, so
+// anything that would alias is filtered out before it can.
+const RESAMPLE_ROLLOFF:f64 = 0.94;
+
+// Zeroth order modified Bessel function of the first kind, for the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term) = (1., 1.);
+    for k in 1..50 {
+        term *= (x / (2. * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 { break }
+    }
+    sum
+}
+
+// Windowed-sinc interpolation of stereo frames. Pulls input at one rate and hands out output at another.
+// Allocates only when made, so it can run in the audio callback.
+pub struct Resampler {
+    step: f64,           // Input frames per output frame
+    half: usize,         // Taps either side of the point being interpolated
+    table: Vec<f32>,     // Kernel from the center out, RESAMPLE_PHASES values per input frame, ending in zeroes
+    input: Vec<[f32; 2]>, // The last 2*half input frames, oldest first
+    pos: f64,            // Where the next output falls, 0..1 of the way from input[half-1] to input[half]
+}
+
+impl Resampler {
+    pub fn new(input_rate: f32, output_rate: f32) -> Self {
+        let step = input_rate as f64 / output_rate as f64;
+        // Downsampling, the kernel widens to cut at the output's Nyquist frequency instead of the input's
+        let cutoff = step.recip().min(1.) * RESAMPLE_ROLLOFF;
+        let half = (RESAMPLE_ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+
+        let norm = bessel_i0(RESAMPLE_KAISER_BETA);
+        let table = (0..half*RESAMPLE_PHASES + 2).map(|idx| {
+            let x = idx as f64 / RESAMPLE_PHASES as f64;
+            if x >= half as f64 { return 0. }
+            let sinc = if x == 0. { 1. } else { (std::f64::consts::PI*cutoff*x).sin() / (std::f64::consts::PI*cutoff*x) };
+            let window = bessel_i0(RESAMPLE_KAISER_BETA * (1. - (x / half as f64).powi(2)).sqrt()) / norm;
+            (cutoff * sinc * window) as f32
+        }).collect();
+
+        Resampler { step, half, table, input: vec![[0.; 2]; 2*half], pos: 0. }
+    }
+
+    fn kernel(&self, x: f64) -> f32 {
+        let at = x.abs() * RESAMPLE_PHASES as f64;
+        let idx = at as usize;
+        if idx + 1 >= self.table.len() { return 0. }
+        let frac = (at - idx as f64) as f32;
+        self.table[idx]*(1.-frac) + self.table[idx+1]*frac
+    }
+
+    // One output frame, taking as many input frames from next_input as that needs (on average, step of them)
+    pub fn next_frame(&mut self, mut next_input: impl FnMut() -> [f32; 2]) -> [f32; 2] {
+        while self.pos >= 1. {
+            self.input.copy_within(1.., 0);
+            let last = self.input.len() - 1;
+            self.input[last] = next_input();
+            self.pos -= 1.;
+        }
+
+        let mut out = [0.; 2];
+        for (idx, frame) in self.input.iter().enumerate() {
+            let weight = self.kernel(idx as f64 - (self.half - 1) as f64 - self.pos);
+            out[0] += frame[0] * weight;
+            out[1] += frame[1] * weight;
+        }
+        self.pos += self.step;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Power of what's left of out after taking away the best-fitting (least squares) sine at frequency,
+    // relative to that sine
+    fn residual_db(out: &[f32], frequency: f64, rate: f64) -> f64 {
+        let basis = |idx: usize| {
+            let phase = 2. * std::f64::consts::PI * frequency * idx as f64 / rate;
+            (phase.sin(), phase.cos())
+        };
+        let (mut ss, mut sc, mut cc, mut ys, mut yc) = (0., 0., 0., 0., 0.);
+        for (idx, &sample) in out.iter().enumerate() {
+            let (sin, cos) = basis(idx);
+            (ss, sc, cc) = (ss + sin*sin, sc + sin*cos, cc + cos*cos);
+            (ys, yc) = (ys + sample as f64*sin, yc + sample as f64*cos);
+        }
+        let det = ss*cc - sc*sc;
+        let (a, b) = ((ys*cc - yc*sc) / det, (yc*ss - ys*sc) / det);
+        let error:f64 = out.iter().enumerate().map(|(idx, &sample)| {
+            let (sin, cos) = basis(idx);
+            (sample as f64 - a*sin - b*cos).powi(2)
+        }).sum::<f64>() / out.len() as f64;
+        10. * (error / ((a*a + b*b) / 2.)).log10()
+    }
+
+    // A sine in the passband comes out as the same sine at the new rate, in both directions
+    #[test]
+    fn sine_passes_through() {
+        for (input_rate, output_rate) in [(48000., 44100.), (44100., 48000.), (48000., 48000.), (48000., 96000.)] {
+            let frequency = 1000.;
+            let mut resampler = Resampler::new(input_rate, output_rate);
+            let mut idx = 0;
+            let mut input = || {
+                let sample = (2. * std::f64::consts::PI * frequency * idx as f64 / input_rate as f64).sin() as f32 * 0.5;
+                idx += 1;
+                [sample, -sample]
+            };
+            // Past the kernel's startup
+            let out:Vec<[f32; 2]> = (0..8192).map(|_| resampler.next_frame(&mut input)).skip(4096).collect();
+            let left:Vec<f32> = out.iter().map(|frame| frame[0]).collect();
+            let rms = (left.iter().map(|sample| sample*sample).sum::<f32>() / left.len() as f32).sqrt();
+            assert!((rms - 0.5/2f32.sqrt()).abs() < 0.01, "{} -> {}: RMS {}", input_rate, output_rate, rms);
+            let residual = residual_db(&left, frequency, output_rate as f64);
+            assert!(residual < -60., "{} -> {}: residual {} dB", input_rate, output_rate, residual);
+            assert!(out.iter().all(|frame| (frame[0] + frame[1]).abs() < 1e-6));
+        }
+    }
+
+    // Downsampling, what's above the new Nyquist frequency is filtered out rather than aliased
+    #[test]
+    fn filters_above_nyquist() {
+        let (input_rate, output_rate, frequency) = (48000., 22050., 15000.);
+        let mut resampler = Resampler::new(input_rate, output_rate);
+        let mut idx = 0;
+        let mut input = || {
+            let sample = (2. * std::f64::consts::PI * frequency * idx as f64 / input_rate as f64).sin() as f32;
+            idx += 1;
+            [sample; 2]
+        };
+        let out:Vec<f32> = (0..8192).map(|_| resampler.next_frame(&mut input)[0]).skip(4096).collect();
+        let rms = (out.iter().map(|sample| sample*sample).sum::<f32>() / out.len() as f32).sqrt();
+        assert!(20. * rms.log10() < -60., "RMS {}", rms);
+    }
+}
@@ -0,0 +1,88 @@
+// Scan heads: the lines across the picture that get read back and played, each as a voice of its own
+
+use crate::constants::*;
+
+// Where a head reads from. Coordinates are fractions of the window, 0,0 at the top left and 1,1 at the bottom right.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeadPath {
+    Fixed { y: f32 },                            // Straight across, at one height
+    Sweep { from: f32, to: f32, period: f32 },   // Straight across, moving from one height to another and back every period seconds
+    Line { x: f32, y: f32, angle: f32, spin: f32 }, // Through a point at an angle (degrees, clockwise from left-to-right), turning spin times a second
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanHeadConfig {
+    pub path: HeadPath,
+    pub gain: f32,  // Linear
+    pub pan: f32,   // -1 (left) to 1 (right)
+    pub curve: f32, // Steepness of the exponential warp from position along the head to frequency; 0 for linear
+}
+
+impl Default for ScanHeadConfig {
+    // The bottom edge, where new rows come in
+    fn default() -> Self {
+        ScanHeadConfig { path: HeadPath::Fixed { y: 1. }, gain: 1., pan: 0., curve: READBACK_CURVE }
+    }
+}
+
+impl ScanHeadConfig {
+    // Start and end of the head, time seconds in
+    pub fn endpoints(&self, time: f32) -> ([f32; 2], [f32; 2]) {
+        match self.path {
+            HeadPath::Fixed { y } => ([0., y], [1., y]),
+            HeadPath::Sweep { from, to, period } => {
+                let phase = (time / period.max(f32::MIN_POSITIVE)).fract();
+                let y = from + (to - from) * (1. - (2.*phase - 1.).abs()); // Triangle wave
+                ([0., y], [1., y])
+            },
+            HeadPath::Line { x, y, angle, spin } => {
+                let angle = (angle + spin*360.*time).to_radians();
+                let (dx, dy) = (angle.cos(), angle.sin());
+                // Run the line out in both directions to the edges of the window
+                let (mut near, mut far) = (f32::NEG_INFINITY, f32::INFINITY);
+                for (pos, dir) in [(x, dx), (y, dy)] {
+                    if dir.abs() > 1e-6 {
+                        let (a, b) = ((0. - pos)/dir, (1. - pos)/dir);
+                        near = near.max(a.min(b));
+                        far = far.min(a.max(b));
+                    }
+                }
+                if near > far { (near, far) = (0., 0.) } // The point is off screen and the line misses
+                ([x + dx*near, y + dy*near], [x + dx*far, y + dy*far])
+            },
+        }
+    }
+
+    // Contents of the ScanHead uniform in shader.wgsl, for a head len pixels long (padded to 32 bytes)
+    pub fn uniform(&self, time: f32, len: usize) -> [u32; 8] {
+        let (start, end) = self.endpoints(time);
+        [start[0].to_bits(), start[1].to_bits(), end[0].to_bits(), end[1].to_bits(), self.curve.to_bits(), len as u32, 0, 0]
+    }
+
+    // Two triangles covering the head, width pixels wide in a window size pixels across, for drawing with vs_plain
+    pub fn marker_vertices(&self, time: f32, size: [f32; 2], width: f32) -> [[f32; 2]; 6] {
+        let (start, end) = self.endpoints(time);
+        let (dx, dy) = ((end[0] - start[0])*size[0], (end[1] - start[1])*size[1]);
+        let len = (dx*dx + dy*dy).sqrt().max(f32::MIN_POSITIVE);
+        let side = [-dy/len*width/2./size[0], dx/len*width/2./size[1]];
+        let corner = |point: [f32; 2], sign: f32| [point[0] + side[0]*sign, point[1] + side[1]*sign];
+        // Clockwise on screen, to survive back-face culling
+        [corner(start, -1.), corner(end, -1.), corner(end, 1.),
+         corner(start, -1.), corner(end, 1.), corner(start, 1.)]
+    }
+
+    // Balance: how loud this head is in the left and right channels. Centered, both are at full gain.
+    pub fn channel_gains(&self) -> [f32; 2] {
+        let pan = self.pan.clamp(-1., 1.);
+        [self.gain * (1. - pan).min(1.), self.gain * (1. + pan).min(1.)]
+    }
+}
+
+// Position along the head (0..1) a pixel u (0..1) of the readback row comes from. Must match readback_along in shader.wgsl.
+pub fn readback_warp(u: f64, curve: f64) -> f64 {
+    if curve > 0. {
+        (1. - (-u*curve).exp2()) / (1. - (-curve).exp2())
+    } else {
+        u
+    }
+}
@@ -0,0 +1,590 @@
+// Command line and config file settings
+
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::audio::{AudioBackend, AudioDeviceConfig, OutputConfig};
+use crate::constants::*;
+use crate::dither::DitherMode;
+use crate::control::{AudioTap, ThresholdMode, VisualConfig};
+use crate::effects::*;
+use crate::input::{InputConfig, InputSource};
+use crate::midi::MidiConfig;
+use crate::osc::OSC_ADDRESSES;
+use crate::readback::Precision;
+use crate::recorder::RecordConfig;
+use crate::scan::{HeadPath, ScanHeadConfig};
+use crate::synth::*;
+
+pub const USAGE:&str = "\
+Options:
+    --config FILE          Read options from FILE: one per line, as key = value, or just key for flags
+                           (keys are the options below without the --; # starts a comment)
+    --speed ROWS           Row shifts per second (default 1.5)
+    --stroke WIDTH         Width of the diagonals, as a fraction of a tile from 0.01 to 0.5 (default 0.1)
+    --tiles N              Tiles across the shorter side of the window, 1 to 200 (default 5)
+    --seed N               Seed the random tile flips, to get the same maze every run
+    --threshold LOW[,HIGH] Blur levels between LOW and HIGH form the blobs' soft edges (default 0.6,0.7)
+    --threshold-mode MODE  soft (ramp between the edges, the default) or hard (cut off halfway between them)
+    --threshold-invert     Swap blobs and background
+    --blur-passes N        Number of blur passes, 1 to 32 (default 8)
+    --blur-radius PX       Distance the first pair of blur passes samples at, in pixels of a 120-pixel tile; the blur
+                           scales with the tiles, so it looks the same at any window size or DPI (default 1)
+    --blur-growth FACTOR   How much further each pair of blur passes reaches than the last (default 2)
+    --precision BITS       Bits per pixel of the blur and readback textures: 8 (default), 16 or 32 (floats). More bits
+                           make for smoother amplitude changes, at the cost of GPU memory and bandwidth
+    --tap STAGE            Stage of the picture the audio hears: grid (before blurring), a number of blur passes,
+                           final (the last blur before thresholding, the default) or threshold (as on screen)
+    --head SPEC            Add a scan head: a line across the picture read back and played as a voice of its own (give
+                           more than once for several; with none, one reads along the bottom edge). SPEC is a path
+                           and optional name=value parameters, as in 'sweep from=0.2 to=0.8 period=4 pan=-0.5'.
+                           Positions are fractions of the window, from the top left:
+                             fixed   y
+                             sweep   from, to (heights), period (seconds there and back)
+                             line    x, y (a point it passes through), angle (degrees), spin (turns per second)
+                           Any path also takes gain (linear), pan (-1 left to 1 right) and curve (how steeply
+                           frequency rises along the head, 0 for linear; default 20). Any pan makes output stereo
+    --markers              Draw the scan heads on screen
+    --input-file PATH      Steer the maze from a WAV file: louder frequency bands (low on the left, high on the right)
+                           make the tiles in their columns more likely to flip. The file loops, and isn't played
+    --input-device NAME    Steer the maze from a sound input device instead, by name or index ('default' for the default)
+    --input-amount A       How strongly the input steers, from 0 (not at all) to 1 (it decides every flip) (default 0.8)
+    --input-floor DB       Input band level, in dBFS, that counts as silence (default -60)
+    --volume DB            Master volume in dB (default 0; + and - keys change it while running)
+    --mute                 Start muted (M toggles mute while running)
+    --no-agc               Turn off automatic gain (leaving the synthesizer's raw level, which varies with the picture)
+    --agc-target DB        RMS level automatic gain aims for, in dBFS (default -20)
+    --agc-attack S         Seconds for automatic gain to react to things getting louder (default 0.05)
+    --agc-release S        Seconds for automatic gain to react to things getting quieter (default 1)
+    --agc-max-gain DB      Most automatic gain will ever boost by (default 30)
+    --dc-cutoff HZ         Cutoff of the DC-blocking filter, 0 for none (default 10)
+    --limiter-ceiling DB   Peak output level, in dBFS (default -1)
+    --limiter-lookahead MS How far ahead the limiter looks, which is also the latency it adds (default 5)
+    --limiter-release S    Seconds for the limiter to recover after a peak (default 0.1)
+    --bits N               Dither output down to N bits (8 to 32), if the device's sample format isn't narrower already.
+                           Also the depth recordings are made at. A 24-bit device in a 32-bit format wants 24
+    --dither MODE          How output and recordings narrower than 32 bits are rounded: tpdf (default), shaped
+                           (tpdf with the noise moved up where it's harder to hear) or none
+    --effect SPEC          Add an effect to the end of the chain (give more than once for several). SPEC is a name
+                           and optional name=value parameters, as in 'delay time=0.5 feedback=0.6':
+                             delay   time (seconds), feedback, damping, mix
+                             reverb  size, damping, mix
+                             filter  mode (lowpass, highpass, bandpass, notch), cutoff (Hz), q
+                             chorus  rate (Hz), depth (seconds), delay (seconds), mix
+                           Amounts are 0 to 1, mix runs from dry (0) to wet (1), and times are at most 10 seconds
+    --fft-size N           Samples per audio synthesis window, a power of two from 256 to 16384 (default 2048). Bigger
+                           sizes resolve pitch more finely but blur changes over time; the screen is read back at
+                           half this many pixels, one per frequency bin
+    --overlap N            Windows overlapping at each moment (default 2), so a new window starts every FFT size/N samples
+    --hop SAMPLES          Samples between audio synthesis windows, overriding --overlap (at most half the FFT size)
+    --window NAME          Synthesis window: hann, hamming, blackman, nuttall, triangular
+    --synth-rate HZ        Sample rate the synthesizer runs at (default 48000), which fixes the frequency each bin plays
+                           at. Sound is resampled from this to the device's rate, so pitch doesn't change with the device
+    --underrun POLICY      When the screen stops sending audio: fade (to silence) or hold (last sound)
+    --synth MODE           How the screen becomes sound: spectral (inverse FFT, the default), granular (grains of a
+                           sample, with brightness setting how many, and position along the row setting where in the
+                           sample they come from and how high they play) or wavetable (the row is one cycle of a
+                           waveform, played at --wavetable-pitch)
+    --grain-source PATH    WAV file granular mode takes grains from (default: the last few seconds of spectral output)
+    --grain-length S       Length of each grain in seconds (default 0.08)
+    --grain-density N      Grains per second with the whole row lit (default 200)
+    --grain-pitch ST       Semitones grains are shifted down at the left of the row and up at the right (default 12)
+    --grain-history S      Seconds of spectral output kept to take grains from, with no --grain-source (default 2)
+    --wavetable-pitch HZ   Pitch wavetable mode plays at (default 110)
+    --audio-backend NAME   Where sound goes: cpal (a sound device), null (nowhere) or file (audio_out.wav)
+    --audio-file PATH      Write sound to this WAV file instead of a sound device
+    --list-devices         Print audio hosts, output devices and the configs they support, then exit
+    --host NAME            Audio host, by name or index
+    --device NAME          Audio output device, by name or index
+    --sample-rate HZ       Audio sample rate
+    --buffer-size FRAMES   Audio buffer size
+    --channels N           Audio channel count
+    --record               Start recording audio to WAV immediately (R toggles recording while running)
+    --record-path PATH     Where to record (default audio_log.wav); later files are numbered
+    --record-max-mb MB     Start a new recording file when the current one reaches this size
+    --record-max-seconds S Start a new recording file when the current one reaches this length
+    --midi-out PATH        Record notes to this Standard MIDI File, written on exit
+    --midi-note-length L   Length of one row shift, as a fraction of a whole note like 1/16 (default 1/16)
+    --midi-threshold T     Amplitude (0 to 1) above which a pitch band plays a note. As in the synth, dark pixels are
+                           loud, so 0.5 means darker than mid gray (default 0.5)
+    --midi-tempo BPM       Tempo written to the MIDI file (default: whatever matches the speed rows shift)
+    --osc-out HOST:PORT    Send OSC over UDP. Each readback sends /readback with the row number, seconds since
+                           start and a blob of one texel per bin (0 loud, 1 silent; bytes by default, little-endian
+                           half or single floats with --precision 16 or 32), and /readback/1, /readback/2... for any
+                           further --head; each row shift sends /row
+                           with the row number, then 0 or 1 per tile of the new row (1 if flipped)
+    --osc-in PORT          Listen for OSC control messages on this UDP port (see below)
+    --help                 Print this message
+";
+
+#[derive(Default, Debug)]
+pub struct Settings {
+    pub synth: SynthConfig,
+    pub output: OutputConfig,
+    pub effects: Vec<EffectConfig>,
+    pub audio_backend: AudioBackend,
+    pub audio_device: AudioDeviceConfig,
+    pub list_devices: bool,
+    pub record: RecordConfig,
+    pub volume_db: f32,
+    pub mute: bool,
+    pub midi: MidiConfig,
+    pub osc_out: Option<String>,
+    pub osc_in: Option<u16>,
+    pub visuals: VisualConfig,
+    pub precision: Precision,
+    pub heads: Vec<ScanHeadConfig>,
+    pub markers: bool,
+    pub input: InputConfig,
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    UnknownKey(String),
+    MissingValue(String),
+    BadValue { key: String, value: String, expected: String },
+    File { path: String, line: usize, error: Box<SettingsError> },
+    Io { path: String, error: std::io::Error },
+    Help,
+}
+
+impl std::error::Error for SettingsError {}
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::UnknownKey(key) => write!(f, "Unknown option '{}'\n\n{}", key, USAGE),
+            SettingsError::MissingValue(key) => write!(f, "Option '{}' needs a value", key),
+            SettingsError::BadValue { key, value, expected } => write!(f, "Bad value '{}' for option '{}': expected {}", value, key, expected),
+            SettingsError::File { path, line, error } => write!(f, "{}:{}: {}", path, line, error),
+            SettingsError::Io { path, error } => write!(f, "Couldn't read '{}': {}", path, error),
+            SettingsError::Help => write!(f, "{}\nOSC messages understood by --osc-in:\n{}", USAGE, OSC_ADDRESSES),
+        }
+    }
+}
+
+fn bad_value(key:&str, value:&str, expected:&str) -> SettingsError {
+    SettingsError::BadValue { key:key.to_string(), value:value.to_string(), expected:expected.to_string() }
+}
+
+fn parse_value<T: FromStr>(key:&str, value:&str, expected:&str) -> Result<T, SettingsError> {
+    value.parse::<T>().map_err(|_| bad_value(key, value, expected))
+}
+
+// A number that also has to fall within range (which NaN never does)
+fn parse_within<T: FromStr + PartialOrd>(key:&str, value:&str, range:RangeInclusive<T>, expected:&str) -> Result<T, SettingsError> {
+    let number = parse_value(key, value, expected)?;
+    if range.contains(&number) { Ok(number) } else { Err(bad_value(key, value, expected)) }
+}
+
+impl FromStr for WindowKind {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hann" | "hanning" => Ok(WindowKind::Hann),
+            "hamming" => Ok(WindowKind::Hamming),
+            "blackman" => Ok(WindowKind::Blackman),
+            "nuttall" => Ok(WindowKind::Nuttall),
+            "triangular" => Ok(WindowKind::Triangular),
+            _ => Err(())
+        }
+    }
+}
+
+impl FromStr for UnderrunPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hold" => Ok(UnderrunPolicy::Hold),
+            "fade" => Ok(UnderrunPolicy::Fade),
+            _ => Err(())
+        }
+    }
+}
+
+impl FromStr for SynthMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "spectral" => Ok(SynthMode::Spectral),
+            "granular" => Ok(SynthMode::Granular),
+            "wavetable" => Ok(SynthMode::Wavetable),
+            _ => Err(())
+        }
+    }
+}
+
+impl FromStr for AudioTap {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "grid" => Ok(AudioTap::Grid),
+            "final" => Ok(AudioTap::Final),
+            "threshold" => Ok(AudioTap::Threshold),
+            passes => passes.parse().map(AudioTap::Blur).map_err(|_| ())
+        }
+    }
+}
+
+impl FromStr for ThresholdMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "soft" => Ok(ThresholdMode::Soft),
+            "hard" => Ok(ThresholdMode::Hard),
+            _ => Err(())
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "8" => Ok(Precision::Unorm8),
+            "16" => Ok(Precision::Float16),
+            "32" => Ok(Precision::Float32),
+            _ => Err(())
+        }
+    }
+}
+
+impl FromStr for DitherMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DitherMode::None),
+            "tpdf" => Ok(DitherMode::Tpdf),
+            "shaped" => Ok(DitherMode::Shaped),
+            _ => Err(())
+        }
+    }
+}
+
+impl FromStr for FilterMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lowpass" => Ok(FilterMode::Lowpass),
+            "highpass" => Ok(FilterMode::Highpass),
+            "bandpass" => Ok(FilterMode::Bandpass),
+            "notch" => Ok(FilterMode::Notch),
+            _ => Err(())
+        }
+    }
+}
+
+// An effect is its name, then any number of name=value parameters; parameters left out keep their defaults
+fn parse_effect(key:&str, value:&str) -> Result<EffectConfig, SettingsError> {
+    let mut words = value.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let mut effect = match name.to_ascii_lowercase().as_str() {
+        "delay" => EffectConfig::Delay(Default::default()),
+        "reverb" => EffectConfig::Reverb(Default::default()),
+        "filter" => EffectConfig::Filter(Default::default()),
+        "chorus" => EffectConfig::Chorus(Default::default()),
+        _ => return Err(bad_value(key, value, "an effect: delay, reverb, filter or chorus"))
+    };
+    for word in words {
+        let Some((param, param_value)) = word.split_once('=') else {
+            return Err(bad_value(key, word, "a parameter, as name=value"));
+        };
+        let number = |range:RangeInclusive<f32>, expected:&str| parse_within(key, param_value, range, expected);
+        let amount = || number(0. ..=1., "an amount from 0 to 1");
+        let time = || number(0. ..=EFFECT_TIME_MAX, &format!("a time in seconds, up to {}", EFFECT_TIME_MAX));
+        let positive = |expected:&str| number(f32::MIN_POSITIVE..=f32::MAX, expected);
+        match (&mut effect, param) {
+            (EffectConfig::Delay(delay), "time") => delay.time = time()?,
+            (EffectConfig::Delay(delay), "feedback") => delay.feedback = amount()?,
+            (EffectConfig::Delay(delay), "damping") => delay.damping = amount()?,
+            (EffectConfig::Delay(delay), "mix") => delay.mix = amount()?,
+            (EffectConfig::Reverb(reverb), "size") => reverb.size = amount()?,
+            (EffectConfig::Reverb(reverb), "damping") => reverb.damping = amount()?,
+            (EffectConfig::Reverb(reverb), "mix") => reverb.mix = amount()?,
+            (EffectConfig::Filter(filter), "mode") => filter.mode = parse_value(key, param_value, "lowpass, highpass, bandpass or notch")?,
+            (EffectConfig::Filter(filter), "cutoff") => filter.cutoff = positive("a frequency above 0 Hz")?,
+            (EffectConfig::Filter(filter), "q") => filter.q = positive("a number above 0")?,
+            (EffectConfig::Chorus(chorus), "rate") => chorus.rate = number(0. ..=f32::MAX, "a frequency in Hz")?,
+            (EffectConfig::Chorus(chorus), "depth") => chorus.depth = time()?,
+            (EffectConfig::Chorus(chorus), "delay") => chorus.delay = time()?,
+            (EffectConfig::Chorus(chorus), "mix") => chorus.mix = amount()?,
+            _ => return Err(bad_value(key, word, &format!("a parameter {} takes", name)))
+        }
+    }
+    Ok(effect)
+}
+
+// A scan head is its path, then any number of name=value parameters; parameters left out keep their defaults
+fn parse_head(key:&str, value:&str) -> Result<ScanHeadConfig, SettingsError> {
+    let mut words = value.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let path = match name.to_ascii_lowercase().as_str() {
+        "fixed" => HeadPath::Fixed { y: 0.5 },
+        "sweep" => HeadPath::Sweep { from: 0., to: 1., period: 4. },
+        "line" => HeadPath::Line { x: 0.5, y: 0.5, angle: 0., spin: 0. },
+        _ => return Err(bad_value(key, value, "a scan head: fixed, sweep or line"))
+    };
+    let mut head = ScanHeadConfig { path, ..Default::default() };
+    for word in words {
+        let Some((param, param_value)) = word.split_once('=') else {
+            return Err(bad_value(key, word, "a parameter, as name=value"));
+        };
+        let number = |range:RangeInclusive<f32>, expected:&str| parse_within(key, param_value, range, expected);
+        let height = || number(0. ..=1., "a fraction of the window height, 0 to 1");
+        let finite = |expected:&str| number(f32::MIN..=f32::MAX, expected);
+        match (&mut head.path, param) {
+            (_, "gain") => head.gain = number(0. ..=f32::MAX, "a linear gain, 0 or more")?,
+            (_, "pan") => head.pan = number(-1. ..=1., "a pan from -1 to 1")?,
+            (_, "curve") => head.curve = number(0. ..=f32::MAX, "a steepness of 0 or more, 0 for linear")?,
+            (HeadPath::Fixed { y }, "y") => *y = height()?,
+            (HeadPath::Sweep { from, .. }, "from") => *from = height()?,
+            (HeadPath::Sweep { to, .. }, "to") => *to = height()?,
+            (HeadPath::Sweep { period, .. }, "period") => *period = number(f32::MIN_POSITIVE..=f32::MAX, "a time above 0 seconds")?,
+            (HeadPath::Line { x, .. }, "x") => *x = number(0. ..=1., "a fraction of the window width, 0 to 1")?,
+            (HeadPath::Line { y, .. }, "y") => *y = height()?,
+            (HeadPath::Line { angle, .. }, "angle") => *angle = finite("an angle in degrees")?,
+            (HeadPath::Line { spin, .. }, "spin") => *spin = finite("turns per second")?,
+            _ => return Err(bad_value(key, word, &format!("a parameter {} takes", name)))
+        }
+    }
+    Ok(head)
+}
+
+impl Settings {
+    // The scan heads to read back, in order; the first is the one MIDI hears
+    pub fn heads(&self) -> Vec<ScanHeadConfig> {
+        if self.heads.is_empty() { vec![ScanHeadConfig::default()] } else { self.heads.clone() }
+    }
+
+    // Set one option by name. Keys are given without the leading "--".
+    pub fn apply(&mut self, key:&str, value:&str) -> Result<(), SettingsError> {
+        match key {
+            "config" => self.load_file(Path::new(value))?,
+            "speed" => {
+                self.visuals.speed = parse_value(key, value, "row shifts per second")?;
+                if !(self.visuals.speed >= GRID_ANIMATE_SPEED_MIN && self.visuals.speed.is_finite()) {
+                    return Err(bad_value(key, value, &format!("at least {}", GRID_ANIMATE_SPEED_MIN)));
+                }
+            },
+            "stroke" => {
+                self.visuals.stroke = parse_value(key, value, "a fraction of a tile")?;
+                if !(STROKE_MIN..=STROKE_MAX).contains(&self.visuals.stroke) {
+                    return Err(bad_value(key, value, &format!("{} to {}", STROKE_MIN, STROKE_MAX)));
+                }
+            },
+            "tiles" => {
+                self.visuals.tiles = parse_value(key, value, "a whole number of tiles")?;
+                if !(1..=TILES_MAX).contains(&self.visuals.tiles) { return Err(bad_value(key, value, &format!("1 to {}", TILES_MAX))) }
+            },
+            "seed" => self.visuals.seed = Some(parse_value(key, value, "a whole number")?),
+            "threshold" => {
+                let expected = "a level from 0 to 1, or two as LOW,HIGH";
+                self.visuals.threshold = match value.split_once(',') {
+                    Some((low, high)) => (parse_value(key, low.trim(), expected)?, parse_value(key, high.trim(), expected)?),
+                    None => {
+                        let low:f32 = parse_value(key, value, expected)?;
+                        (low, low + 0.1)
+                    }
+                };
+                let (low, high) = self.visuals.threshold;
+                if !(low.is_finite() && high.is_finite() && high >= low + THRESHOLD_EDGE_MIN) { return Err(bad_value(key, value, "a low level below the high one")) }
+            },
+            "threshold-mode" => self.visuals.threshold_mode = parse_value(key, value, "soft or hard")?,
+            "blur-passes" => {
+                let passes:usize = parse_value(key, value, "a whole number of passes")?;
+                if passes == 0 || passes > BLUR_PASSES_MAX {
+                    return Err(bad_value(key, value, &format!("1 to {}", BLUR_PASSES_MAX)));
+                }
+                self.visuals.blur_passes = passes;
+            },
+            "blur-radius" => {
+                let radius:f32 = parse_value(key, value, "a distance in pixels")?;
+                if !(radius > 0. && radius.is_finite()) { return Err(bad_value(key, value, "a distance above 0")) }
+                self.visuals.blur_radius = radius;
+            },
+            "blur-growth" => {
+                let growth:f32 = parse_value(key, value, "a factor")?;
+                if !(growth > 0. && growth.is_finite()) { return Err(bad_value(key, value, "a factor above 0")) }
+                self.visuals.blur_growth = growth;
+            },
+            "precision" => self.precision = parse_value(key, value, "8, 16 or 32 bits")?,
+            "tap" => self.visuals.tap = parse_value(key, value, "grid, final, threshold or a number of blur passes")?,
+            "input-file" => self.input.source = Some(InputSource::File(value.into())),
+            "input-device" => self.input.source = Some(InputSource::Device(if value == "default" { None } else { Some(value.to_string()) })),
+            "input-amount" => self.input.amount = parse_value(key, value, "an amount from 0 to 1")?,
+            "input-floor" => {
+                self.input.floor_db = parse_value(key, value, "a level in dBFS")?;
+                if self.input.floor_db >= 0. { return Err(bad_value(key, value, "a level below 0 dBFS")) }
+            },
+            "osc-in" => self.osc_in = Some(parse_value(key, value, "a UDP port number")?),
+            "volume" => self.volume_db = parse_within(key, value, VOLUME_DB_MIN..=VOLUME_DB_MAX, &format!("a gain from {} to {} dB", VOLUME_DB_MIN, VOLUME_DB_MAX))?,
+            "agc-target" => self.output.agc_target_db = parse_within(key, value, -120. ..=0., "a level from -120 to 0 dBFS")?,
+            "agc-attack" => self.output.agc_attack = parse_within(key, value, 0. ..=60., "a time from 0 to 60 seconds")?,
+            "agc-release" => self.output.agc_release = parse_within(key, value, 0. ..=60., "a time from 0 to 60 seconds")?,
+            "agc-max-gain" => self.output.agc_max_gain_db = parse_within(key, value, 0. ..=120., "a gain from 0 to 120 dB")?,
+            "dc-cutoff" => self.output.dc_cutoff = parse_within(key, value, 0. ..=1000., "a frequency from 0 to 1000 Hz")?,
+            "limiter-ceiling" => self.output.limiter_ceiling_db = parse_within(key, value, -120. ..=0., "a level from -120 to 0 dBFS")?,
+            "limiter-lookahead" => {
+                let ms:f32 = parse_within(key, value, 0. ..=1000., "a time from 0 to 1000 milliseconds")?;
+                self.output.limiter_lookahead = ms/1000.;
+            },
+            "limiter-release" => self.output.limiter_release = parse_within(key, value, 0. ..=60., "a time from 0 to 60 seconds")?,
+            "bits" => {
+                let bits:u32 = parse_value(key, value, "a bit depth")?;
+                if !(8..=32).contains(&bits) { return Err(bad_value(key, value, "8 to 32")) }
+                self.output.bits = Some(bits);
+            },
+            "dither" => self.output.dither = parse_value(key, value, "tpdf, shaped or none")?,
+            "effect" => self.effects.push(parse_effect(key, value)?),
+            "head" => self.heads.push(parse_head(key, value)?),
+            "fft-size" => {
+                let len:usize = parse_value(key, value, "a whole number of samples")?;
+                if !len.is_power_of_two() || !(AUDIO_CHUNK_LEN_MIN..=AUDIO_CHUNK_LEN_MAX).contains(&len) {
+                    return Err(bad_value(key, value, &format!("a power of two from {} to {}", AUDIO_CHUNK_LEN_MIN, AUDIO_CHUNK_LEN_MAX)));
+                }
+                self.synth.fft_len = len;
+            },
+            "overlap" => {
+                let overlap:usize = parse_value(key, value, "a whole number of windows")?;
+                if overlap < 2 { return Err(bad_value(key, value, "at least 2")) }
+                self.synth.overlap = overlap;
+            },
+            "hop" => {
+                let hop:usize = parse_value(key, value, "a whole number of samples")?;
+                if hop == 0 { return Err(bad_value(key, value, "at least 1")) }
+                self.synth.hop = Some(hop);
+            },
+            "window" => self.synth.window = parse_value(key, value, "hann, hamming, blackman, nuttall or triangular")?,
+            "synth-rate" => {
+                let rate:u32 = parse_value(key, value, "a rate in Hz")?;
+                if !(8000..=192000).contains(&rate) { return Err(bad_value(key, value, "a rate from 8000 to 192000 Hz")) }
+                self.synth.sample_rate = rate;
+            },
+            "underrun" => self.synth.underrun = parse_value(key, value, "fade or hold")?,
+            "synth" => self.synth.mode = parse_value(key, value, "spectral, granular or wavetable")?,
+            "grain-source" => self.synth.granular.source = Some(value.into()),
+            "grain-length" | "grain-density" | "grain-history" => {
+                let amount:f32 = parse_value(key, value, "a number above 0")?;
+                if !(amount > 0. && amount.is_finite()) { return Err(bad_value(key, value, "a number above 0")) }
+                match key {
+                    "grain-length" => self.synth.granular.grain_length = amount,
+                    "grain-density" => self.synth.granular.density = amount,
+                    _ => self.synth.granular.history = amount,
+                }
+            },
+            "wavetable-pitch" => {
+                let pitch:f32 = parse_value(key, value, "a frequency in Hz")?;
+                if !(pitch > 0. && pitch.is_finite()) { return Err(bad_value(key, value, "a frequency above 0 Hz")) }
+                self.synth.wavetable.pitch = pitch;
+            },
+            "grain-pitch" => self.synth.granular.pitch_range = parse_value(key, value, "a number of semitones")?,
+            "audio-backend" => self.audio_backend = match value.to_ascii_lowercase().as_str() {
+                "cpal" => AudioBackend::Cpal,
+                "null" => AudioBackend::Null,
+                "file" => AudioBackend::File("audio_out.wav".into()),
+                _ => return Err(bad_value(key, value, "cpal, null or file"))
+            },
+            "audio-file" => self.audio_backend = AudioBackend::File(value.into()),
+            "host" => self.audio_device.host = Some(value.to_string()),
+            "device" => self.audio_device.device = Some(value.to_string()),
+            "sample-rate" => self.audio_device.sample_rate = Some(parse_within(key, value, 1..=u32::MAX, "a rate in Hz, more than 0")?),
+            "buffer-size" => self.audio_device.buffer_size = Some(parse_within(key, value, 1..=u32::MAX, "a whole number of frames, more than 0")?),
+            "channels" => self.audio_device.channels = Some(parse_within(key, value, 1..=u16::MAX, "a whole number of channels, more than 0")?),
+            "midi-out" => self.midi.path = Some(value.into()),
+            "midi-note-length" => {
+                let expected = "a fraction of a whole note, like 1/16 or 0.25";
+                let length:f64 = match value.split_once('/') {
+                    Some((num, den)) => parse_value::<f64>(key, num.trim(), expected)? / parse_value::<f64>(key, den.trim(), expected)?,
+                    None => parse_value(key, value, expected)?
+                };
+                if !(length > 0. && length.is_finite()) {
+                    return Err(bad_value(key, value, expected));
+                }
+                self.midi.note_length = length;
+            },
+            "midi-threshold" => self.midi.threshold = parse_within(key, value, 0. ..=1., "an amplitude from 0 to 1")?,
+            "midi-tempo" => {
+                let bpm:f64 = parse_value(key, value, "beats per minute")?;
+                if !(bpm > 0. && bpm.is_finite()) { return Err(bad_value(key, value, "more than 0 beats per minute")) }
+                self.midi.tempo = Some(bpm);
+            },
+            "osc-out" => self.osc_out = Some(value.to_string()),
+            "record-path" => self.record.path = value.into(),
+            "record-max-mb" => {
+                let mb:f64 = parse_value(key, value, "a size in megabytes")?;
+                if !(mb > 0. && mb.is_finite()) { return Err(bad_value(key, value, "more than 0")) }
+                self.record.max_bytes = Some(((mb*1024.*1024.) as u64).max(1));
+            },
+            "record-max-seconds" => {
+                let seconds:f64 = parse_value(key, value, "a length in seconds")?;
+                if !(seconds > 0. && seconds.is_finite()) { return Err(bad_value(key, value, "more than 0")) }
+                self.record.max_seconds = Some(seconds);
+            },
+            _ => return Err(SettingsError::UnknownKey(key.to_string()))
+        }
+        Ok(())
+    }
+
+    // Catch combinations of options that are fine one by one but not together
+    fn check(&self) -> Result<(), SettingsError> {
+        let hop = self.synth.hop();
+        if hop == 0 || hop > self.synth.fft_len/2 {
+            let key = if self.synth.hop.is_some() { "hop" } else { "overlap" };
+            let value = self.synth.hop.unwrap_or(self.synth.overlap).to_string();
+            return Err(bad_value(key, &value, &format!("a hop of 1 to {} samples, with an FFT size of {}", self.synth.fft_len/2, self.synth.fft_len)));
+        }
+        Ok(())
+    }
+
+    // Set an option that takes no value
+    fn apply_flag(&mut self, key:&str) -> Result<bool, SettingsError> {
+        match key {
+            "help" => return Err(SettingsError::Help),
+            "list-devices" => self.list_devices = true,
+            "record" => self.record.enabled = true,
+            "mute" => self.mute = true,
+            "markers" => self.markers = true,
+            "threshold-invert" => self.visuals.threshold_invert = true,
+            "no-agc" => self.output.agc = false,
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    // Apply every line of a config file in order
+    pub fn load_file(&mut self, path: &Path) -> Result<(), SettingsError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| SettingsError::Io { path: path.display().to_string(), error })?;
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() { continue }
+            let result = match line.split_once('=') {
+                Some((key, value)) => self.apply(key.trim(), value.trim()),
+                None => match self.apply_flag(line) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(SettingsError::MissingValue(line.to_string())),
+                    Err(e) => Err(e)
+                }
+            };
+            result.map_err(|error| SettingsError::File { path: path.display().to_string(), line: line_idx+1, error: Box::new(error) })?;
+        }
+        Ok(())
+    }
+
+    // Options are "--key value" or "--key=value", or just "--key" for flags
+    pub fn from_args(mut args: impl Iterator<Item=String>) -> Result<Settings, SettingsError> {
+        let mut settings = Settings::default();
+        while let Some(arg) = args.next() {
+            let Some(arg) = arg.strip_prefix("--") else { return Err(SettingsError::UnknownKey(arg)) };
+            if settings.apply_flag(arg)? { continue }
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => (arg.to_string(), args.next().ok_or_else(|| SettingsError::MissingValue(arg.to_string()))?),
+            };
+            settings.apply(&key, &value)?;
+        }
+        settings.check()?;
+        Ok(settings)
+    }
+}
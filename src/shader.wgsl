@@ -12,6 +12,12 @@ fn fs_plain() -> @location(0) vec4<f32> {
     return vec4<f32>(0.0, 0.0, 0.0, 1.0);
 }
 
+// Scan head markers; a color so they stand out from the gray picture
+@fragment
+fn fs_marker() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0, 0.2, 0.2, 1.0);
+}
+
 struct Textured {
     @location(0) tex_coord: vec2<f32>,
     @builtin(position) position: vec4<f32>,
@@ -51,15 +57,6 @@ fn fs_textured(vertex: Textured) -> @location(0) vec4<f32> {
     return vec4(value, value, value, 1.0);
 }
 
-// Y is fixed at max; X is an exponential curve
-const readback_curve = 20.; // FIXME: Match to x_across?
-@fragment
-fn fs_textured_readback(vertex: Textured) -> @location(0) vec4<f32> {
-    let x = 1. - pow(2., -vertex.tex_coord.x*readback_curve);
-    let value = textureSample(gray, gray_sampler, vec2(x, 1.)).r;
-    return vec4(value, value, value, 1.0);
-}
-
 // This one-dimensional separable blur filter samples five points and averages them by different amounts.
 // If we do it on two separate axes, we get a 2d blur.
 // Weights and offsets taken from http://rastergrid.com/blog/2010/09/efficient-gaussian-blur-with-linear-sampling/
@@ -73,12 +70,19 @@ const WEIGHT2 = 0.0702702703;
 const OFFSET1 = 1.3846153846;
 const OFFSET2 = 3.2307692308;
 
+struct BlurParams {
+    resolution: vec2<f32>, // Step between samples
+    threshold: vec2<f32>,  // Low and high edge, final pass only
+    invert: u32,           // Nonzero to swap blobs and background, final pass only
+    hard: u32,             // Nonzero to cut off at the middle of the edges instead of ramping between them, final pass only
+};
+
 @group(0)
 @binding(2)
-var<uniform> blur_resolution: vec2<f32>;
+var<uniform> blur: BlurParams;
 
 fn separable_blur(uv:vec2<f32>) -> f32 {
-    var blurVector:vec2<f32> = blur_resolution;
+    var blurVector:vec2<f32> = blur.resolution;
     var color = 0.0;
     color += textureSample(gray, gray_sampler, uv).r * WEIGHT0;
     color += textureSample(gray, gray_sampler, uv + blurVector * OFFSET1).r * WEIGHT1;
@@ -99,8 +103,13 @@ fn fs_postprocess_blur(vertex: Textured) -> @location(0) vec4<f32> {
 @fragment
 fn fs_postprocess_blur_threshold(vertex: Textured) -> @location(0) vec4<f32> {
     let prevalue = separable_blur(vertex.tex_coord);
-    var value = smoothstep(0.6, 0.7, prevalue); // .4, .5 are also interesting. TODO scale to size of window
-//    if (prevalue > 0.65) { value = 1.0; }
+    var value:f32;
+    if (blur.hard != 0u) {
+        value = step(mix(blur.threshold.x, blur.threshold.y, 0.5), prevalue);
+    } else {
+        value = smoothstep(blur.threshold.x, blur.threshold.y, prevalue);
+    }
+    if (blur.invert != 0u) { value = 1.0 - value; }
     return vec4(value, value, value, 1.0);
 }
 
@@ -123,3 +132,70 @@ fn internal_copy(@builtin(global_invocation_id) global_id: vec3<u32>) {
     }
 }
 
+// Readback: gray sampled along a scan head, any number of pixels long, spaced along an exponential curve.
+// Packed into 32-bit words exactly as a texture of the matching format would copy out, padded at the end with zeroes.
+
+struct ScanHead {
+    start: vec2<f32>, // 0..1, Y-down
+    end: vec2<f32>,
+    curve: f32,       // 0 for evenly spaced
+    len: u32,         // Pixels
+};
+
+@group(0)
+@binding(1)
+var<storage, read_write> readback_out: array<u32>;
+
+@group(0)
+@binding(2)
+var<uniform> head: ScanHead;
+
+// Must match readback_warp in scan.rs
+fn readback_along(u: f32) -> f32 {
+    if (head.curve > 0.) {
+        return (1. - pow(2., -u*head.curve)) / (1. - pow(2., -head.curve));
+    }
+    return u;
+}
+
+fn readback_value(idx: u32) -> f32 {
+    if (idx >= head.len) {
+        return 0.;
+    }
+    let size = textureDimensions(gray);
+    let position = mix(head.start, head.end, readback_along((f32(idx) + 0.5)/f32(head.len)));
+    let texel = clamp(vec2<i32>(position*vec2<f32>(size)), vec2(0), vec2<i32>(size) - 1);
+    return textureLoad(gray, texel, 0).r;
+}
+
+@compute
+@workgroup_size(64)
+fn readback_unorm8(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let word = global_id.x;
+    if (word >= arrayLength(&readback_out)) {
+        return;
+    }
+    let idx = word*4u;
+    readback_out[word] = pack4x8unorm(vec4(readback_value(idx), readback_value(idx + 1u), readback_value(idx + 2u), readback_value(idx + 3u)));
+}
+
+@compute
+@workgroup_size(64)
+fn readback_float16(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let word = global_id.x;
+    if (word >= arrayLength(&readback_out)) {
+        return;
+    }
+    let idx = word*2u;
+    readback_out[word] = pack2x16float(vec2(readback_value(idx), readback_value(idx + 1u)));
+}
+
+@compute
+@workgroup_size(64)
+fn readback_float32(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let word = global_id.x;
+    if (word >= arrayLength(&readback_out)) {
+        return;
+    }
+    readback_out[word] = bitcast<u32>(readback_value(word));
+}
@@ -0,0 +1,452 @@
+// Turn spectral frames from the screen into samples
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use realfft::{ComplexToReal, num_complex::Complex};
+use web_time::Duration;
+
+use crate::audio::AudioControls;
+use crate::constants::*;
+use crate::granular::{Granular, GranularConfig};
+use crate::resample::Resampler;
+use crate::wavetable::{Wavetable, WavetableConfig};
+use crate::worker::WorkerThread;
+
+// A spectral frame as read back from the screen, stamped with the time (in seconds since the
+// graphics thread started) that it was drawn. Amplitudes are 0..1, one per frequency bin (SynthConfig::bins of them).
+pub struct AudioFrame {
+    pub time: f64,
+    pub spectrum: Vec<f32>,
+}
+
+// Frames go round in a loop: filled on the readback side, played by a Resynth, then handed back here to be filled
+// again. So the audio side never frees a frame (or the Vec inside it), and once enough are going round, nobody allocates.
+#[derive(Clone)]
+pub struct FramePool {
+    send: crossbeam_channel::Sender<Box<AudioFrame>>,
+    recv: crossbeam_channel::Receiver<Box<AudioFrame>>,
+}
+
+impl FramePool {
+    // For voices Resynths, each fed by a channel of inflight frames. A new frame is only made when the pool is empty,
+    // so every frame there will ever be fits back in: inflight in each channel, a history in each Resynth, and one being filled.
+    pub fn new(voices: usize, inflight: usize) -> Self {
+        let (send, recv) = crossbeam_channel::bounded(voices*(inflight + AUDIO_FRAME_HISTORY) + 1);
+        FramePool { send, recv }
+    }
+
+    // A frame to fill, with room for bins amplitudes
+    pub fn take(&self, bins: usize) -> Box<AudioFrame> {
+        self.recv.try_recv().unwrap_or_else(|_| Box::new(AudioFrame { time: 0., spectrum: Vec::with_capacity(bins) }))
+    }
+
+    // Done with a frame. Never blocks; the pool is sized so it never has to.
+    pub fn give(&self, frame: Box<AudioFrame>) {
+        self.send.try_send(frame).ok();
+    }
+}
+
+// How far behind the newest frame the audio playhead trails, in seconds.
+// Must be comfortably longer than one video frame, or we will be interpolating against nothing.
+const AUDIO_LATENCY:f64 = 0.1;
+
+// If the playhead falls this far behind the newest frame (say, after a stall) jump forward instead of catching up.
+const AUDIO_RESYNC_LATENCY:f64 = AUDIO_LATENCY*3.;
+
+// Maximum number of frames the audio thread will hold on to while waiting for the playhead to reach them
+const AUDIO_FRAME_HISTORY:usize = 16;
+
+// With every bin at full amplitude and random phases, the inverse FFT comes out with an RMS of about sqrt(2*bins).
+// Dividing by that puts a fully lit row near full scale; the output stage in audio.rs decides the final level.
+fn synth_scale(bins: usize) -> f64 {
+    1. / (2. * (bins-1) as f64).sqrt()
+}
+
+// When frames stop arriving and UnderrunPolicy::Fade is in effect, go from full volume to silence over this many seconds
+const AUDIO_UNDERRUN_FADE:f64 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowKind {
+    Hann,
+    Hamming,
+    Blackman,
+    Nuttall,
+    Triangular,
+}
+
+impl WindowKind {
+    // Periodic (not symmetric) window of the given length. Periodic windows are the ones that overlap-add cleanly.
+    fn generate(self, len: usize) -> Vec<f64> {
+        let mut window:Vec<f64> = match self {
+            WindowKind::Hann => apodize::hanning_iter(len+1).collect(),
+            WindowKind::Hamming => apodize::hamming_iter(len+1).collect(),
+            WindowKind::Blackman => apodize::blackman_iter(len+1).collect(),
+            WindowKind::Nuttall => apodize::nuttall_iter(len+1).collect(),
+            WindowKind::Triangular => apodize::triangular_iter(len+1).collect(),
+        };
+        window.truncate(len);
+        window
+    }
+}
+
+// How frames become sound
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SynthMode {
+    Spectral, // Each frame is a spectrum, played through an inverse FFT
+    Granular, // Each frame scatters grains of a sample (see granular.rs)
+    Wavetable, // Each frame is one cycle of a waveform, played at a fixed pitch (see wavetable.rs)
+}
+
+// What Resynth plays the frames with, once it knows where the playhead is
+pub enum SynthEngine {
+    Spectral,
+    Granular(Box<Granular>),
+    Wavetable(Box<Wavetable>),
+}
+
+// What to do when the playhead runs past the newest frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnderrunPolicy {
+    Hold, // Keep resynthesizing the last spectrum we saw
+    Fade, // Fade toward silence until frames arrive again
+}
+
+#[derive(Clone, Debug)]
+pub struct SynthConfig {
+    pub fft_len: usize,     // Samples per window. A power of two, AUDIO_CHUNK_LEN_MIN to AUDIO_CHUNK_LEN_MAX
+    pub overlap: usize,     // Windows overlapping each sample, if hop isn't given
+    pub hop: Option<usize>, // Samples between successive windows. Must be between 1 and fft_len/2
+    pub window: WindowKind,
+    pub underrun: UnderrunPolicy,
+    pub mode: SynthMode,
+    pub granular: GranularConfig,
+    pub wavetable: WavetableConfig,
+    pub sample_rate: u32, // Rate synthesis happens at, in Hz; bin idx plays at idx*sample_rate/fft_len
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        SynthConfig { fft_len: AUDIO_CHUNK_LEN, overlap: AUDIO_OVERLAP, hop: None, window: WindowKind::Hann, underrun: UnderrunPolicy::Fade,
+            mode: SynthMode::Spectral, granular: Default::default(), wavetable: Default::default(), sample_rate: AUDIO_SYNTH_RATE }
+    }
+}
+
+impl SynthConfig {
+    // Frequency bins, which is also the length of the readback row
+    pub fn bins(&self) -> usize { self.fft_len/2 }
+
+    pub fn hop(&self) -> usize { self.hop.unwrap_or(self.fft_len / self.overlap.max(1)) }
+}
+
+// Counters the audio thread updates for anyone curious how well it's being fed
+#[derive(Default, Debug)]
+pub struct AudioStats {
+    pub underruns: AtomicU64,    // Times the playhead ran past the newest frame
+    pub starved_hops: AtomicU64, // Hops synthesized with no fresh frame to interpolate toward
+    pub resyncs: AtomicU64,      // Times the playhead fell so far behind it jumped forward
+    pub late_samples: AtomicU64, // Samples the device wanted before the synthesis thread had them ready
+    pub dropped_rows: AtomicU64, // Rows read back while the readback thread was too far behind to take them
+}
+
+impl AudioStats {
+    pub fn report(&self) {
+        println!("Audio: {} underruns ({} hops starved), {} resyncs, {} samples late, {} rows dropped",
+            self.underruns.load(Ordering::Relaxed), self.starved_hops.load(Ordering::Relaxed), self.resyncs.load(Ordering::Relaxed),
+            self.late_samples.load(Ordering::Relaxed), self.dropped_rows.load(Ordering::Relaxed));
+    }
+}
+
+// Resynthesizes spectral frames into samples, on the audio thread's own clock.
+// Every hop we work out where the playhead is relative to the timestamps of the frames we have,
+// interpolate between the two frames that bracket it, run an inverse FFT with random phases and
+// overlap-add the result. How often frames arrive therefore affects only how fresh the sound is,
+// never whether it is continuous.
+pub struct Resynth {
+    config: SynthConfig,
+    hop_len: usize,
+    bins: usize,
+    stats: Arc<AudioStats>,
+    controls: Arc<AudioControls>,
+
+    fft: Arc<dyn ComplexToReal<f64>>,
+    fft_in: Vec<Complex<f64>>,
+    fft_out: Vec<f64>,
+    fft_scratch: Vec<Complex<f64>>,
+    window: Vec<f64>, // Includes synth_scale()
+    rng: StdRng,
+
+    frame_recv: crossbeam_channel::Receiver<Box<AudioFrame>>,
+    frame_pool: FramePool, // Where frames go once played, instead of being freed
+    frames: VecDeque<Box<AudioFrame>>,
+    playhead: Option<f64>, // In the graphics thread's clock, seconds
+    hop_seconds: f64,
+    starved: bool,
+    gain: f64, // Underrun fade
+
+    overlap: Vec<f64>, // Overlap-add accumulator, fft_len long; first hop_len samples are ready to play
+    overlap_norm: Vec<f64>, // Reciprocal of the summed windows at each position in a hop, so any window/hop pair sums to 1
+    overlap_idx: usize,
+
+    engine: SynthEngine, // Anything but Spectral takes over from the inverse FFT (or, for grains, may play what it made)
+}
+
+impl Resynth {
+    // The engine is made beforehand, since loading a granular sample can fail
+    pub fn new(sample_rate: f32, config: SynthConfig, stats: Arc<AudioStats>, controls: Arc<AudioControls>, frame_recv: crossbeam_channel::Receiver<Box<AudioFrame>>, frame_pool: FramePool, engine: SynthEngine) -> Self {
+        let (fft_len, hop_len, bins) = (config.fft_len, config.hop(), config.bins());
+        assert!(hop_len > 0 && hop_len <= fft_len/2, "Hop size {} out of range 1..={}", hop_len, fft_len/2);
+
+        let mut fft_planner = realfft::RealFftPlanner::<f64>::new();
+        let fft = fft_planner.plan_fft_inverse(fft_len);
+        let (fft_in, fft_out, fft_scratch) = (fft.make_input_vec(), fft.make_output_vec(), fft.make_scratch_vec());
+
+        let mut window = config.window.generate(fft_len);
+        let overlap_norm = (0..hop_len).map(|idx|
+            1. / window.iter().skip(idx).step_by(hop_len).sum::<f64>()
+        ).collect();
+        for sample in window.iter_mut() {
+            *sample *= synth_scale(bins);
+        }
+
+        Resynth {
+            fft, fft_in, fft_out, fft_scratch, window,
+            rng: StdRng::from_entropy(),
+            frame_recv, frame_pool,
+            frames: VecDeque::with_capacity(AUDIO_FRAME_HISTORY),
+            playhead: None,
+            hop_seconds: hop_len as f64 / sample_rate as f64,
+            starved: true,
+            gain: 1.,
+            overlap: vec![0.; fft_len],
+            overlap_norm,
+            overlap_idx: hop_len,
+            engine,
+            hop_len, bins,
+            config, stats, controls,
+        }
+    }
+
+    // Pick up any frames the graphics thread has sent, and move the playhead up to date.
+    fn update_frames(&mut self) {
+        // While muted the graphics thread stops reading back, so forget where we were; we'll start fresh on unmute.
+        // With no playhead we also stop synthesizing, and what's left in the overlap buffer fades out along the window.
+        if self.controls.muted() {
+            while let Ok(frame) = self.frame_recv.try_recv() {
+                self.frame_pool.give(frame);
+            }
+            for frame in self.frames.drain(..) {
+                self.frame_pool.give(frame);
+            }
+            self.playhead = None;
+            return;
+        }
+
+        while let Ok(frame) = self.frame_recv.try_recv() {
+            if self.frames.len() >= AUDIO_FRAME_HISTORY {
+                if let Some(oldest) = self.frames.pop_front() {
+                    self.frame_pool.give(oldest);
+                }
+            }
+            self.frames.push_back(frame);
+        }
+
+        let Some(newest) = self.frames.back().map(|frame| frame.time) else { return };
+        let playhead = match self.playhead {
+            Some(playhead) if newest - playhead <= AUDIO_RESYNC_LATENCY => playhead,
+            Some(_) => {
+                self.stats.resyncs.fetch_add(1, Ordering::Relaxed);
+                newest - AUDIO_LATENCY
+            },
+            None => newest - AUDIO_LATENCY, // First frame
+        };
+        self.playhead = Some(playhead);
+
+        // Drop frames that no longer bracket the playhead
+        while self.frames.len() >= 2 && self.frames[1].time <= playhead {
+            if let Some(oldest) = self.frames.pop_front() {
+                self.frame_pool.give(oldest);
+            }
+        }
+    }
+
+    // Amplitude of bin idx at the current playhead. Before the first frame and after the last we hold the nearest one.
+    fn amplitude(&self, idx: usize) -> f64 {
+        let (Some(playhead), Some(before)) = (self.playhead, self.frames.front()) else { return 0. };
+        match self.frames.get(1) {
+            Some(after) if playhead > before.time => {
+                let t = ((playhead - before.time) / (after.time - before.time)).clamp(0., 1.);
+                (before.spectrum[idx] as f64)*(1.-t) + (after.spectrum[idx] as f64)*t
+            },
+            _ => before.spectrum[idx] as f64
+        }
+    }
+
+    // Check whether we've run out of frames, and move the underrun fade accordingly
+    fn update_underrun(&mut self) {
+        let Some(playhead) = self.playhead else { return }; // Nothing has arrived yet, so nothing has run out
+        let starved = !self.frames.back().is_some_and(|newest| playhead < newest.time);
+        if starved {
+            if !self.starved {
+                self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+            }
+            self.stats.starved_hops.fetch_add(1, Ordering::Relaxed);
+        }
+        self.starved = starved;
+
+        let target = if starved && self.config.underrun == UnderrunPolicy::Fade { 0. } else { 1. };
+        let step = self.hop_seconds / AUDIO_UNDERRUN_FADE;
+        self.gain = if self.gain < target { (self.gain + step).min(target) } else { (self.gain - step).max(target) };
+    }
+
+    // Synthesize one windowed block and add it into the overlap buffer
+    fn hop(&mut self) {
+        let hop = self.hop_len;
+        self.update_frames();
+        self.update_underrun();
+
+        // Retire the samples we just played
+        self.overlap.copy_within(hop.., 0);
+        let fft_len = self.overlap.len();
+        self.overlap[fft_len-hop..].fill(0.);
+
+        let active = self.playhead.is_some() && self.gain > 0.;
+        let spectral = match &self.engine {
+            SynthEngine::Spectral => true,
+            SynthEngine::Granular(granular) => granular.wants_spectral(),
+            SynthEngine::Wavetable(_) => false,
+        };
+        if active && spectral {
+            // Bin 0 (DC) and the Nyquist bin are left silent
+            self.fft_in[0] = Complex::default();
+            self.fft_in[self.bins] = Complex::default();
+            for idx in 0..(self.bins-1) {
+                let phase = self.rng.gen::<f64>() * 2. * std::f64::consts::PI;
+                let ampl = self.amplitude(idx) * self.gain;
+                self.fft_in[idx+1] = Complex { re:phase.cos()*ampl, im:phase.sin()*ampl };
+            }
+            self.fft.process_with_scratch(&mut self.fft_in, &mut self.fft_out, &mut self.fft_scratch).unwrap();
+
+            for (idx, sample) in self.overlap.iter_mut().enumerate() {
+                *sample += self.fft_out[idx] * self.window[idx];
+            }
+        }
+
+        // Taken out for the moment, so it can look at the frames while it's updated
+        let engine = std::mem::replace(&mut self.engine, SynthEngine::Spectral);
+        let level = |idx| if active { self.amplitude(idx) * self.gain } else { 0. };
+        match engine {
+            SynthEngine::Spectral => {},
+            SynthEngine::Granular(mut granular) => {
+                granular.update(level);
+                self.engine = SynthEngine::Granular(granular);
+            },
+            SynthEngine::Wavetable(mut wavetable) => {
+                wavetable.update(level);
+                self.engine = SynthEngine::Wavetable(wavetable);
+            },
+        }
+
+        if let Some(playhead) = self.playhead.as_mut() {
+            *playhead += self.hop_seconds;
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        if self.overlap_idx >= self.hop_len {
+            self.hop();
+            self.overlap_idx = 0;
+        }
+        let out = (self.overlap[self.overlap_idx] * self.overlap_norm[self.overlap_idx]) as f32;
+        self.overlap_idx += 1;
+        match &mut self.engine {
+            SynthEngine::Spectral => out,
+            SynthEngine::Granular(granular) => granular.next_sample(out),
+            SynthEngine::Wavetable(wavetable) => wavetable.next_sample(),
+        }
+    }
+}
+
+// One scan head's synthesizer, and where it sits in the mix
+pub struct Voice {
+    pub resynth: Resynth,
+    pub gains: [f32; 2], // Left and right, see ScanHeadConfig::channel_gains
+}
+
+// Every voice, mixed down to stereo frames, and resampled from the synthesizer's rate to the device's
+pub struct Mixer {
+    voices: Vec<Voice>,
+    resampler: Option<Resampler>, // None if the rates match
+    fft_len: usize,
+    hop_len: usize,
+}
+
+impl Mixer {
+    // All the voices should have been made with this config
+    pub fn new(voices: Vec<Voice>, config: &SynthConfig, output_rate: f32) -> Self {
+        let input_rate = config.sample_rate as f32;
+        let resampler = (input_rate != output_rate).then(|| Resampler::new(input_rate, output_rate));
+        Mixer { voices, resampler, fft_len: config.fft_len, hop_len: config.hop() }
+    }
+
+    fn mix(voices: &mut [Voice]) -> [f32; 2] {
+        let mut frame = [0.; 2];
+        for voice in voices.iter_mut() {
+            let sample = voice.resynth.next_sample();
+            frame[0] += sample * voice.gains[0];
+            frame[1] += sample * voice.gains[1];
+        }
+        frame
+    }
+
+    pub fn next_frame(&mut self) -> [f32; 2] {
+        match &mut self.resampler {
+            Some(resampler) => resampler.next_frame(|| Mixer::mix(&mut self.voices)),
+            None => Mixer::mix(&mut self.voices),
+        }
+    }
+}
+
+// Where the output stage gets its frames from
+pub enum SynthSource {
+    Inline(Box<Mixer>),                                // Synthesize right there in the audio callback (on the web, where there are no threads)
+    Worker(rtrb::Consumer<[f32; 2]>, Arc<AudioStats>), // Take what the synthesis thread has made
+}
+
+impl SynthSource {
+    pub fn next_frame(&mut self) -> [f32; 2] {
+        match self {
+            SynthSource::Inline(mixer) => mixer.next_frame(),
+            SynthSource::Worker(consumer, stats) => consumer.pop().unwrap_or_else(|_| {
+                stats.late_samples.fetch_add(1, Ordering::Relaxed);
+                [0.; 2]
+            })
+        }
+    }
+}
+
+// Run the synthesizer on a thread of its own, so the audio callback never waits on an FFT. The thread keeps
+// one window's worth of samples queued beyond what one callback (buffer_frames, or a hop if that's more) takes,
+// topping the queue up a hop at a time. Everything queued is already decided, so this adds that much latency;
+// the window only has to cover the time one hop takes to synthesize, plus scheduling jitter.
+pub fn synth_spawn(mut mixer: Mixer, stats: Arc<AudioStats>, sample_rate: f32, buffer_frames: usize) -> (SynthSource, WorkerThread) {
+    let hop = mixer.hop_len;
+    let (mut producer, consumer) = rtrb::RingBuffer::new(buffer_frames.max(hop) + mixer.fft_len);
+    // Check back often enough that the queue never drains by more than a quarter of a hop
+    let poll = Duration::from_secs_f64(hop as f64 / sample_rate as f64 / 4.);
+    let mut fill = move |producer: &mut rtrb::Producer<[f32; 2]>| {
+        while let Ok(chunk) = producer.write_chunk_uninit(hop) {
+            chunk.fill_from_iter(std::iter::repeat_with(|| mixer.next_frame()));
+        }
+    };
+    // Start full, so the device's first callbacks don't race the thread getting going
+    fill(&mut producer);
+    let thread = WorkerThread::spawn("synthesis", move |quit| {
+        while !quit.load(Ordering::Relaxed) {
+            fill(&mut producer);
+            std::thread::sleep(poll);
+        }
+    });
+    (SynthSource::Worker(consumer, stats), thread)
+}
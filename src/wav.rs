@@ -0,0 +1,360 @@
+// Minimal WAV writer, for recording what we play, and reader, for listening to what we're given
+
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::dither::*;
+
+// On-disk sample encodings we can write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WavFormat {
+    U8,
+    I16,
+    I24, // Packed, three bytes a sample
+    I32,
+    F32,
+    F64,
+}
+
+impl WavFormat {
+    // The closest thing WAV can store to what the device plays
+    pub fn from_sample_format(format: cpal::SampleFormat) -> WavFormat {
+        match format {
+            cpal::SampleFormat::U8 => WavFormat::U8,
+            cpal::SampleFormat::I8 | cpal::SampleFormat::I16 | cpal::SampleFormat::U16 => WavFormat::I16,
+            cpal::SampleFormat::I32 | cpal::SampleFormat::U32 => WavFormat::I32,
+            cpal::SampleFormat::F64 => WavFormat::F64,
+            _ => WavFormat::F32,
+        }
+    }
+
+    // The narrowest integer format holding samples of this many bits
+    pub fn from_bits(bits: u32) -> WavFormat {
+        match bits {
+            0..=8 => WavFormat::U8,
+            9..=16 => WavFormat::I16,
+            17..=24 => WavFormat::I24,
+            _ => WavFormat::I32,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            WavFormat::U8 => 1,
+            WavFormat::I16 => 2,
+            WavFormat::I24 => 3,
+            WavFormat::I32 | WavFormat::F32 => 4,
+            WavFormat::F64 => 8,
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, WavFormat::F32 | WavFormat::F64)
+    }
+
+    // Encode one -1..1 sample, little-endian, into out. Formats narrower than 32 bits are dithered.
+    fn encode(self, sample: f32, dither: &mut Dither, out: &mut Vec<u8>) {
+        let clamped = sample.clamp(-1., 1.) as f64;
+        match self {
+            // Same scales as the device conversions and wav_read, so samples already reduced to these depths pass through exactly
+            WavFormat::U8 => out.push((dither.quantize(sample, 128.).min(127.) + 128.) as u8),
+            WavFormat::I16 => out.extend_from_slice(&(dither.quantize(sample, 32768.).min(32767.) as i16).to_le_bytes()),
+            WavFormat::I24 => out.extend_from_slice(&(dither.quantize(sample, I24_SCALE).min(I24_SCALE - 1.) as i32).to_le_bytes()[..3]),
+            WavFormat::I32 => out.extend_from_slice(&((clamped*i32::MAX as f64).round() as i32).to_le_bytes()),
+            WavFormat::F32 => out.extend_from_slice(&sample.to_le_bytes()),
+            WavFormat::F64 => out.extend_from_slice(&(sample as f64).to_le_bytes()),
+        }
+    }
+}
+
+const I24_SCALE:f64 = (1 << 23) as f64;
+
+// Byte offsets of the fields we come back to patch
+const RIFF_SIZE_AT:u64 = 4;
+const JUNK_AT:u64 = 12;        // Placeholder that becomes "ds64" if the file outgrows 4 GB
+const JUNK_LEN:u32 = 28;
+const FACT_COUNT_AT:u64 = 20 + JUNK_LEN as u64 + 8 + 18 + 8; // Float formats only
+const HEADER_PCM_LEN:u64 = 20 + JUNK_LEN as u64 + 8 + 16 + 8;
+const HEADER_FLOAT_LEN:u64 = 20 + JUNK_LEN as u64 + 8 + 18 + 12 + 8;
+
+// Writes a WAV file whose header can be brought up to date at any moment, so a file cut off by a crash
+// is still readable up to the last patch. Files that pass 4 GB are converted in place to RF64.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    format: WavFormat,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u64, // Bytes of sample data written so far
+    dither: Vec<Dither>, // One per channel
+    scratch: Vec<u8>,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, format: WavFormat, channels: u16, sample_rate: u32, dither: DitherMode) -> std::io::Result<WavWriter> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            format, channels, sample_rate,
+            data_len: 0,
+            dither: (0..channels.max(1)).map(|_| Dither::new(dither)).collect(),
+            scratch: Default::default(),
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn header_len(&self) -> u64 {
+        if self.format.is_float() { HEADER_FLOAT_LEN } else { HEADER_PCM_LEN }
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let bytes = self.format.bytes() as u16;
+        let block_align = bytes * self.channels;
+        let mut header:Vec<u8> = Default::default();
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Patched later
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"JUNK");
+        header.extend_from_slice(&JUNK_LEN.to_le_bytes());
+        header.extend_from_slice(&[0; JUNK_LEN as usize]);
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&(if self.format.is_float() { 18u32 } else { 16u32 }).to_le_bytes());
+        header.extend_from_slice(&(if self.format.is_float() { 3u16 } else { 1u16 }).to_le_bytes()); // IEEE float or PCM
+        header.extend_from_slice(&self.channels.to_le_bytes());
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&(bytes*8).to_le_bytes());
+        if self.format.is_float() {
+            header.extend_from_slice(&0u16.to_le_bytes()); // cbSize
+            header.extend_from_slice(b"fact");
+            header.extend_from_slice(&4u32.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes()); // Patched later
+        }
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Patched later
+        debug_assert_eq!(header.len() as u64, self.header_len());
+        self.file.write_all(&header)
+    }
+
+    // Seconds of audio written so far
+    pub fn duration(&self) -> f64 {
+        self.data_len as f64 / (self.format.bytes() * self.channels as usize) as f64 / self.sample_rate as f64
+    }
+
+    // Bytes written so far, header included
+    pub fn file_len(&self) -> u64 {
+        self.header_len() + self.data_len
+    }
+
+    // Samples are interleaved if there is more than one channel, and should come in whole frames
+    pub fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        self.scratch.clear();
+        let channels = self.dither.len();
+        for (idx, &sample) in samples.iter().enumerate() {
+            let dither = &mut self.dither[idx % channels];
+            self.format.encode(sample, dither, &mut self.scratch);
+        }
+        self.file.write_all(&self.scratch)?;
+        self.data_len += self.scratch.len() as u64;
+        Ok(())
+    }
+
+    // Rewrite the size fields to describe everything written so far, and flush
+    pub fn patch_header(&mut self) -> std::io::Result<()> {
+        self.patch(0)
+    }
+
+    fn patch(&mut self, pad: u64) -> std::io::Result<()> {
+        let header_len = self.header_len();
+        let riff_len = header_len - 8 + self.data_len + pad;
+        let frames = self.data_len / (self.format.bytes() * self.channels as usize) as u64;
+        let rf64 = riff_len > u32::MAX as u64;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(if rf64 { b"RF64" } else { b"RIFF" })?;
+        self.file.seek(SeekFrom::Start(RIFF_SIZE_AT))?;
+        self.file.write_all(&(if rf64 { u32::MAX } else { riff_len as u32 }).to_le_bytes())?;
+        if rf64 {
+            self.file.seek(SeekFrom::Start(JUNK_AT))?;
+            self.file.write_all(b"ds64")?;
+            self.file.write_all(&JUNK_LEN.to_le_bytes())?;
+            self.file.write_all(&riff_len.to_le_bytes())?;
+            self.file.write_all(&self.data_len.to_le_bytes())?;
+            self.file.write_all(&frames.to_le_bytes())?;
+            self.file.write_all(&0u32.to_le_bytes())?; // No table
+        }
+        if self.format.is_float() {
+            self.file.seek(SeekFrom::Start(FACT_COUNT_AT))?;
+            self.file.write_all(&(frames.min(u32::MAX as u64) as u32).to_le_bytes())?;
+        }
+        self.file.seek(SeekFrom::Start(header_len - 4))?;
+        self.file.write_all(&(if rf64 { u32::MAX } else { self.data_len as u32 }).to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    // Pad to an even length as RIFF requires, and patch the header one last time
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.finish_in_place()
+    }
+
+    fn finish_in_place(&mut self) -> std::io::Result<()> {
+        if self.finished { return Ok(()) }
+        self.finished = true;
+        let pad = self.data_len%2;
+        if pad == 1 {
+            self.file.write_all(&[0])?;
+        }
+        self.patch(pad)
+    }
+}
+
+// A writer that's simply dropped still leaves a complete file behind
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        self.finish_in_place().ok();
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+// Read a whole WAV file, mixed down to mono -1..1 samples, along with its sample rate.
+// Handles 8/16/24/32-bit PCM and 32/64-bit float, including the extensible variants of each.
+pub fn wav_read(path: &Path) -> std::io::Result<(Vec<f32>, u32)> {
+    let data = std::fs::read(path)?;
+    if data.len() < 12 || !(data.starts_with(b"RIFF") || data.starts_with(b"RF64")) || &data[8..12] != b"WAVE" {
+        return Err(invalid("Not a WAV file"));
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at+1]]);
+    let u32_at = |at: usize| u32::from_le_bytes([data[at], data[at+1], data[at+2], data[at+3]]);
+
+    let mut format:Option<(u16, u16, u32, u16)> = None; // Tag, channels, sample rate, bits
+    let mut samples:Option<&[u8]> = None;
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let len = u32_at(at+4) as usize;
+        let body = &data[at+8..];
+        match &data[at..at+4] {
+            b"fmt " if len >= 16 && body.len() >= 16 => {
+                let mut tag = u16_at(at+8);
+                if tag == 0xFFFE && len >= 26 && body.len() >= 26 { // WAVE_FORMAT_EXTENSIBLE: the real tag starts the subformat GUID
+                    tag = u16_at(at+8+24);
+                }
+                format = Some((tag, u16_at(at+10), u32_at(at+12), u16_at(at+22)));
+            },
+            // RF64 files (and ones cut off before their header was patched) can have a wrong length here; take everything left
+            b"data" => {
+                samples = Some(&body[..len.min(body.len())]);
+                break;
+            },
+            _ => {}
+        }
+        at += 8 + len + len%2;
+    }
+
+    let (Some((tag, channels, sample_rate, bits)), Some(samples)) = (format, samples) else {
+        return Err(invalid("WAV file has no fmt or data chunk"));
+    };
+    if channels == 0 || sample_rate == 0 {
+        return Err(invalid("WAV file has no channels"));
+    }
+    let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+        (1, 8) => |b| (b[0] as f32 - 128.) / 128.,
+        (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.,
+        (1, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648.,
+        (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.,
+        (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        (3, 64) => |b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+        _ => return Err(invalid(&format!("Unsupported WAV encoding (format {}, {} bits)", tag, bits)))
+    };
+    let width = bits as usize / 8;
+    let mono = samples.chunks_exact(width * channels as usize)
+        .map(|frame| frame.chunks_exact(width).map(decode).sum::<f32>() / channels as f32)
+        .collect();
+    Ok((mono, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("wgpu-hello-{}-{}.wav", std::process::id(), name))
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at+4].try_into().unwrap())
+    }
+
+    // Every size field agrees with what was actually written, odd data lengths included
+    #[test]
+    fn header_sizes() {
+        for format in [WavFormat::U8, WavFormat::I16, WavFormat::I24, WavFormat::I32, WavFormat::F32, WavFormat::F64] {
+            for channels in [1u16, 2] {
+                for frames in [0usize, 1, 3, 100] {
+                    let path = temp_path(&format!("{:?}-{}-{}", format, channels, frames));
+                    let mut writer = WavWriter::create(&path, format, channels, 48000, DitherMode::None).unwrap();
+                    let samples:Vec<f32> = (0..frames*channels as usize).map(|idx| (idx as f32 * 0.1).sin() * 0.5).collect();
+                    writer.write_samples(&samples).unwrap();
+                    let header_len = writer.header_len() as usize;
+                    let data_len = frames * channels as usize * format.bytes();
+                    assert_eq!(writer.file_len() as usize, header_len + data_len);
+                    writer.finish().unwrap();
+
+                    let data = std::fs::read(&path).unwrap();
+                    std::fs::remove_file(&path).ok();
+                    let pad = data_len % 2;
+                    assert_eq!(data.len(), header_len + data_len + pad, "{:?}", format);
+                    assert_eq!(&data[..4], b"RIFF");
+                    assert_eq!(u32_at(&data, RIFF_SIZE_AT as usize) as usize, data.len() - 8);
+                    assert_eq!(&data[JUNK_AT as usize..JUNK_AT as usize + 4], b"JUNK");
+                    assert_eq!(&data[header_len-8..header_len-4], b"data");
+                    assert_eq!(u32_at(&data, header_len-4) as usize, data_len);
+                    if format.is_float() {
+                        assert_eq!(&data[FACT_COUNT_AT as usize-8..FACT_COUNT_AT as usize-4], b"fact");
+                        assert_eq!(u32_at(&data, FACT_COUNT_AT as usize) as usize, frames);
+                    }
+                }
+            }
+        }
+    }
+
+    // A header patched mid-recording already describes a complete file
+    #[test]
+    fn patch_mid_recording() {
+        let path = temp_path("patch");
+        let mut writer = WavWriter::create(&path, WavFormat::I16, 1, 44100, DitherMode::None).unwrap();
+        writer.write_samples(&[0.25; 441]).unwrap();
+        writer.patch_header().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(u32_at(&data, RIFF_SIZE_AT as usize) as usize, data.len() - 8);
+        let (samples, sample_rate) = wav_read(&path).unwrap();
+        assert_eq!((samples.len(), sample_rate), (441, 44100));
+        drop(writer);
+        std::fs::remove_file(&path).ok();
+    }
+
+    // What's written reads back the same, to within the format's precision
+    #[test]
+    fn read_back() {
+        for (format, tolerance) in [(WavFormat::I16, 1./32768.), (WavFormat::I24, 1./8388608.), (WavFormat::F32, 0.)] {
+            let path = temp_path(&format!("read-{:?}", format));
+            let samples:Vec<f32> = (0..1000).map(|idx| (idx as f32 * 0.05).sin() * 0.9).collect();
+            let mut writer = WavWriter::create(&path, format, 1, 48000, DitherMode::None).unwrap();
+            writer.write_samples(&samples).unwrap();
+            writer.finish().unwrap();
+            let (read, sample_rate) = wav_read(&path).unwrap();
+            std::fs::remove_file(&path).ok();
+            assert_eq!(sample_rate, 48000);
+            assert_eq!(read.len(), samples.len());
+            for (a, b) in samples.iter().zip(&read) {
+                assert!((a - b).abs() <= tolerance, "{:?}: wrote {}, read {}", format, a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Wavetable synthesis: the readback row as one cycle of a waveform, played by an oscillator
+
+use std::sync::Arc;
+use realfft::{ComplexToReal, RealToComplex, num_complex::Complex};
+
+#[derive(Clone, Debug)]
+pub struct WavetableConfig {
+    pub pitch: f32, // Hz
+}
+
+impl Default for WavetableConfig {
+    fn default() -> Self {
+        WavetableConfig { pitch: 110. }
+    }
+}
+
+// Every hop, Resynth hands over the row, as the amplitudes the spectral synth would play: dark is high, bright low.
+// Those become the height of the wave, left to right, centered so the average is silence. Harmonics that would land above the Nyquist frequency at the oscillator's pitch
+// are cut, and the oscillator crossfades from the previous table to the new one over the course of the hop.
+pub struct Wavetable {
+    phase: f64, // 0..1 through the cycle
+    step: f64,  // Cycles per sample
+    harmonics: usize, // Highest harmonic kept
+    hop_len: usize,
+    since_update: usize,
+
+    forward: Arc<dyn RealToComplex<f64>>,
+    inverse: Arc<dyn ComplexToReal<f64>>,
+    time: Vec<f64>,
+    freq: Vec<Complex<f64>>,
+    scratch: Vec<Complex<f64>>,
+
+    table: Vec<f64>,    // Playing now
+    previous: Vec<f64>, // Fading out
+}
+
+impl Wavetable {
+    pub fn new(config: &WavetableConfig, sample_rate: f32, bins: usize, hop_len: usize) -> Self {
+        let mut fft_planner = realfft::RealFftPlanner::<f64>::new();
+        let forward = fft_planner.plan_fft_forward(bins);
+        let inverse = fft_planner.plan_fft_inverse(bins);
+        let (time, freq) = (forward.make_input_vec(), forward.make_output_vec());
+        let scratch = vec![Complex::default(); forward.get_scratch_len().max(inverse.get_scratch_len())];
+
+        let pitch = config.pitch as f64;
+        Wavetable {
+            phase: 0.,
+            step: pitch / sample_rate as f64,
+            harmonics: ((sample_rate as f64 / 2. / pitch) as usize).min(bins/2),
+            hop_len,
+            since_update: 0,
+            forward, inverse, time, freq, scratch,
+            table: vec![0.; bins],
+            previous: vec![0.; bins],
+        }
+    }
+
+    // Take in the amplitude (0..1, 1 for a dark pixel) of every bin, for the hop about to be played
+    pub fn update(&mut self, level: impl Fn(usize) -> f64) {
+        for (idx, sample) in self.time.iter_mut().enumerate() {
+            *sample = level(idx);
+        }
+        self.forward.process_with_scratch(&mut self.time, &mut self.freq, &mut self.scratch).unwrap();
+
+        // Drop the average (the DC bin) and anything too high to play back cleanly. Doubling what's left
+        // makes a row of half black, half white come out full scale.
+        let len = self.table.len();
+        self.freq[0] = Complex::default();
+        for bin in self.freq.iter_mut().skip(self.harmonics+1) {
+            *bin = Complex::default();
+        }
+        self.inverse.process_with_scratch(&mut self.freq, &mut self.time, &mut self.scratch).unwrap();
+
+        std::mem::swap(&mut self.table, &mut self.previous);
+        for (out, &sample) in self.table.iter_mut().zip(self.time.iter()) {
+            *out = sample * 2. / len as f64;
+        }
+        self.since_update = 0;
+    }
+
+    fn lookup(table: &[f64], phase: f64) -> f64 {
+        let pos = phase * table.len() as f64;
+        let idx = pos as usize % table.len();
+        let frac = pos.fract();
+        table[idx]*(1.-frac) + table[(idx+1) % table.len()]*frac
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let fade = (self.since_update as f64 / self.hop_len as f64).min(1.);
+        let out = Wavetable::lookup(&self.previous, self.phase)*(1.-fade) + Wavetable::lookup(&self.table, self.phase)*fade;
+        self.phase = (self.phase + self.step).fract();
+        self.since_update += 1;
+        out as f32
+    }
+}
@@ -0,0 +1,33 @@
+// Background threads that are told to stop, and waited for, when dropped
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct WorkerThread {
+    quit: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl WorkerThread {
+    // The body should return soon after the flag it's handed becomes true
+    pub fn spawn(name: &str, body: impl FnOnce(Arc<AtomicBool>) + Send + 'static) -> WorkerThread {
+        let quit = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let quit = quit.clone();
+            std::thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || body(quit))
+                .expect("Couldn't spawn thread")
+        };
+        WorkerThread { quit, handle: Some(handle) }
+    }
+}
+
+impl Drop for WorkerThread {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}